@@ -1,16 +1,484 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clap::{Parser, Subcommand};
+use mapreduce::client::Client;
+use mapreduce::format::{InputFormat, OutputFormat};
+use mapreduce::iterate;
+use mapreduce::job::JobSpec;
+use mapreduce::master::Master;
+use mapreduce::models::Partitioner;
+use mapreduce::pipeline::{self, PipelineSpec};
+use mapreduce::registry::JobRegistry;
+use mapreduce::server::ServerConfig;
+use tokio::sync::Mutex;
+
+/// MapReduce master: schedules map and reduce tasks for workers over gRPC.
+#[derive(Parser)]
+#[command(name = "master", version)]
+struct Cli {
+    /// Log level (error, warn, info, debug, trace)
+    #[arg(long, global = true, default_value = "info")]
+    log_level: log::LevelFilter,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run a job and serve its tasks until it completes
+    Run(RunArgs),
+    /// Run a pipeline (DAG) of jobs, each stage reading the output of the stages it depends on
+    #[command(alias = "workflow")]
+    Pipeline(PipelineArgs),
+    /// Run as a long-lived service that accepts jobs with `submit`
+    Serve(ServeArgs),
+    /// Submit a job to a running master
+    Submit(SubmitArgs),
+    /// List the jobs on a master
+    List(MasterArgs),
+    /// List the workers registered with a master
+    Workers(MasterArgs),
+    /// Cancel a job on a master
+    Cancel(CancelArgs),
+    /// Show the phase and counters of a job on a master
+    Status(StatusArgs),
+}
+
+#[derive(clap::Args)]
+struct MasterArgs {
+    /// Master address
+    #[arg(short, long, default_value = "http://127.0.0.1:50051")]
+    master: String,
+}
+
+#[derive(clap::Args)]
+struct StatusArgs {
+    #[command(flatten)]
+    master: MasterArgs,
+
+    /// Job to show; defaults to the most recently submitted job
+    #[arg(short, long)]
+    job: Option<u32>,
+
+    /// Also list every task with its attempt history
+    #[arg(long)]
+    tasks: bool,
+}
+
+#[derive(clap::Args)]
+struct ServeArgs {
+    /// Address to listen on
+    #[arg(short, long, default_value = "127.0.0.1:50051")]
+    bind: SocketAddr,
+
+    /// Seconds between health checks for stuck tasks
+    #[arg(long, value_name = "SECS", default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    health_check_interval: u64,
+
+    /// Seconds without a heartbeat before a worker is expired and its tasks rescheduled
+    #[arg(long, value_name = "SECS", default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    worker_lease: u64,
+}
+
+#[derive(clap::Args)]
+struct SubmitArgs {
+    #[command(flatten)]
+    master: MasterArgs,
+
+    #[command(flatten)]
+    job: JobArgs,
+}
+
+#[derive(clap::Args)]
+struct CancelArgs {
+    #[command(flatten)]
+    master: MasterArgs,
+
+    /// Job to cancel
+    job: u32,
+}
+
+#[derive(clap::Args)]
+struct PipelineArgs {
+    /// Pipeline definition file (TOML) with one [[stages]] table per job
+    #[arg(long, value_name = "FILE")]
+    spec: PathBuf,
+
+    /// Skip stages whose output was committed by an earlier run of the same stage
+    #[arg(long)]
+    resume: bool,
+
+    /// Address to listen on
+    #[arg(short, long, default_value = "127.0.0.1:50051")]
+    bind: SocketAddr,
+
+    /// Seconds without a heartbeat before a worker is expired and its tasks rescheduled
+    #[arg(long, value_name = "SECS", default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    worker_lease: u64,
+}
+
+const JOB_FLAGS: [&str; 13] = [
+    "app",
+    "inputs",
+    "input_format",
+    "output_format",
+    "n_reduce",
+    "partitioner",
+    "output_dir",
+    "task_timeout",
+    "health_check_interval",
+    "backup_threshold",
+    "max_attempts",
+    "config",
+    "cache_files",
+];
+
+#[derive(clap::Args)]
+struct RunArgs {
+    #[command(flatten)]
+    job: JobArgs,
+
+    /// Address to listen on
+    #[arg(short, long, default_value = "127.0.0.1:50051")]
+    bind: SocketAddr,
+
+    /// Seconds without a heartbeat before a worker is expired and its tasks rescheduled
+    #[arg(long, value_name = "SECS", default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    worker_lease: u64,
+}
+
+/// A job, given as a spec file or as individual flags.
+#[derive(clap::Args)]
+struct JobArgs {
+    /// Job specification file (TOML); replaces the individual job flags
+    #[arg(long, value_name = "FILE", conflicts_with_all = JOB_FLAGS)]
+    spec: Option<PathBuf>,
+
+    /// Application to run
+    #[arg(short, long, default_value = "wc")]
+    app: String,
+
+    /// Input files, directories (recursive), glob patterns or @manifest files
+    #[arg(short, long = "input", value_name = "SPEC", default_value = "input")]
+    inputs: Vec<String>,
+
+    /// Input format: text or parquet
+    #[arg(long, default_value = "text")]
+    input_format: InputFormat,
+
+    /// Output format: text, parquet or parquet:<utf8|int64|float64>
+    #[arg(long, default_value = "text")]
+    output_format: OutputFormat,
+
+    /// Number of reduce tasks; 0 runs a map-only job that writes map output directly
+    #[arg(short = 'r', long, default_value_t = 5)]
+    n_reduce: u32,
+
+    /// Partitioner for intermediate keys: hash or range
+    #[arg(long, default_value = "hash")]
+    partitioner: Partitioner,
+
+    /// Directory for intermediate and final output files
+    #[arg(short, long, default_value = "output")]
+    output_dir: String,
+
+    /// Seconds an attempt may run before it is rescheduled; 0 leaves liveness to worker heartbeats
+    #[arg(long, value_name = "SECS", default_value_t = 600)]
+    task_timeout: u64,
+
+    /// Seconds between health checks for stuck tasks
+    #[arg(long, value_name = "SECS", default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    health_check_interval: u64,
+
+    /// Seconds a task must run before a backup copy may be scheduled
+    #[arg(long, value_name = "SECS", default_value_t = 10)]
+    backup_threshold: u64,
+
+    /// Failed or timed-out attempts per task before the job fails
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u32).range(1..))]
+    max_attempts: u32,
+
+    /// Job configuration passed to the app, as KEY=VALUE (repeatable)
+    #[arg(short, long, value_name = "KEY=VALUE", value_parser = parse_key_value)]
+    config: Vec<(String, String)>,
+
+    /// Side file made available to every task through the distributed cache (repeatable)
+    #[arg(long = "cache-file", value_name = "PATH")]
+    cache_files: Vec<String>,
+}
+
+fn parse_key_value(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .ok_or_else(|| format!("expected KEY=VALUE, got '{}'", s))
+}
+
+impl JobArgs {
+    fn job_spec(&self) -> Result<JobSpec, Box<dyn std::error::Error>> {
+        if let Some(path) = &self.spec {
+            return JobSpec::from_file(path);
+        }
+        let mut spec = JobSpec::new(&self.app, self.inputs.clone());
+        spec.input_format = self.input_format;
+        spec.output_format = self.output_format;
+        spec.n_reduce = self.n_reduce;
+        spec.partitioner = self.partitioner;
+        spec.output_dir = self.output_dir.clone();
+        spec.task_timeout_secs = self.task_timeout;
+        spec.health_check_interval_secs = self.health_check_interval;
+        spec.backup_threshold_secs = self.backup_threshold;
+        spec.max_attempts = self.max_attempts;
+        spec.config = self.config.iter().cloned().collect();
+        spec.cache_files = self.cache_files.clone();
+        spec.validate()?;
+        Ok(spec)
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
     env_logger::Builder::new()
-        .filter_level(log::LevelFilter::Info)
+        .filter_level(cli.log_level)
         .init();
 
-    let args: Vec<String> = std::env::args().collect();
-    let port = args.get(1).map(|s| s.as_str()).unwrap_or("50051");
+    match cli.command {
+        Command::Run(args) => run(args).await,
+        Command::Pipeline(args) => run_pipeline(args).await,
+        Command::Serve(args) => serve(args).await,
+        Command::Submit(args) => submit(args).await,
+        Command::List(args) => list(args)
+            .await
+            .map_err(|e| e as Box<dyn std::error::Error>),
+        Command::Workers(args) => workers(args)
+            .await
+            .map_err(|e| e as Box<dyn std::error::Error>),
+        Command::Cancel(args) => cancel(args)
+            .await
+            .map_err(|e| e as Box<dyn std::error::Error>),
+        Command::Status(args) => status(args)
+            .await
+            .map_err(|e| e as Box<dyn std::error::Error>),
+    }
+}
+
+async fn status(args: StatusArgs) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut client = Client::connect(&args.master.master).await?;
+    let status = client.job_status(args.job).await?;
+    if let Some(job_id) = status.job_id {
+        println!("job: {} ({})", job_id, status.name);
+    }
+    println!("phase: {}", status.phase);
+    if let Some(failure) = &status.failure {
+        println!("failure: {}", failure);
+    }
+    for (kind, counts) in [("map", &status.map_tasks), ("reduce", &status.reduce_tasks)] {
+        if let Some(c) = counts {
+            println!(
+                "{} tasks: {} idle, {} in progress, {} completed",
+                kind, c.idle, c.in_progress, c.completed
+            );
+        }
+    }
+    match status.eta_secs {
+        Some(secs) => println!("eta: {}s", secs),
+        None if status.phase == "failed" || status.phase == "cancelled" => {}
+        None => println!("eta: unknown"),
+    }
+    println!(
+        "backups: {} launched, {} won",
+        status.backups_launched, status.backups_won
+    );
+    for b in &status.blacklisted {
+        println!("blacklisted: {} ({}s left)", b.worker, b.remaining_secs);
+    }
+    if args.tasks {
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        println!("tasks:");
+        for task in &status.tasks {
+            println!("  {} {} {}", task.task_type, task.task_id, task.state);
+            for a in &task.attempts {
+                let ago = now_ms.saturating_sub(a.start_unix_ms) / 1000;
+                println!(
+                    "    attempt {} on {} started {}s ago, ran {}ms{} {}{}",
+                    a.attempt_id,
+                    a.worker,
+                    ago,
+                    a.duration_ms,
+                    if a.backup { " (backup)" } else { "" },
+                    a.outcome,
+                    match &a.error {
+                        _ if a.outcome == "running" => format!(" ({:.0}%)", a.progress * 100.0),
+                        Some(error) => format!(": {}", error),
+                        None => String::new(),
+                    }
+                );
+            }
+        }
+    }
+    println!("counters:");
+    let counters: BTreeMap<_, _> = status.counters.into_iter().collect();
+    for (name, value) in counters {
+        println!("  {} {}", name, value);
+    }
+    if !status.stages.is_empty() {
+        println!("stages:");
+        for stage in &status.stages {
+            let job = stage
+                .job_id
+                .map(|id| format!(" job {}", id))
+                .unwrap_or_default();
+            println!(
+                "  {} {}{} -> {} (after: {})",
+                stage.name,
+                stage.state,
+                job,
+                stage.output_dir,
+                stage.depends_on.join(", ")
+            );
+        }
+    }
+    if !status.spec.is_empty() {
+        println!("spec:");
+        for line in status.spec.lines() {
+            println!("  {}", line);
+        }
+    }
+    Ok(())
+}
+
+async fn serve(args: ServeArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let config = ServerConfig {
+        addr: args.bind,
+        health_check_interval: Duration::from_secs(args.health_check_interval),
+        worker_lease: Duration::from_secs(args.worker_lease),
+    };
+    let registry = Arc::new(Mutex::new(JobRegistry::new()));
+    mapreduce::server::serve(registry, Arc::default(), config).await
+}
+
+async fn submit(args: SubmitArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let spec = args.job.job_spec()?;
+    let mut client = Client::connect(&args.master.master)
+        .await
+        .map_err(|e| e as Box<dyn std::error::Error>)?;
+    let job_id = client
+        .submit_job(&spec)
+        .await
+        .map_err(|e| e as Box<dyn std::error::Error>)?;
+    println!("{}", job_id);
+    Ok(())
+}
+
+async fn list(args: MasterArgs) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut client = Client::connect(&args.master).await?;
+    println!(
+        "{:>4}  {:<20} {:<10} {:<7} {:>9} {:>9}",
+        "ID", "NAME", "APP", "PHASE", "MAPS", "REDUCES"
+    );
+    for job in client.list_jobs().await? {
+        println!(
+            "{:>4}  {:<20} {:<10} {:<7} {:>9} {:>9}",
+            job.job_id,
+            job.name,
+            job.app,
+            job.phase,
+            format!("{}/{}", job.maps_done, job.maps_total),
+            format!("{}/{}", job.reduces_done, job.reduces_total)
+        );
+    }
+    Ok(())
+}
+
+async fn workers(args: MasterArgs) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut client = Client::connect(&args.master).await?;
+    println!(
+        "{:>4}  {:<24} {:>7} {:>9}",
+        "ID", "NAME", "SLOTS", "HEARTBEAT"
+    );
+    for w in client.list_workers().await? {
+        println!(
+            "{:>4}  {:<24} {:>7} {:>9}",
+            w.worker_id,
+            w.name,
+            format!("{}/{}", w.running, w.slots),
+            format!("{:.1}s ago", w.heartbeat_age_ms as f64 / 1000.0)
+        );
+    }
+    Ok(())
+}
+
+async fn cancel(args: CancelArgs) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut client = Client::connect(&args.master.master).await?;
+    client.cancel_job(args.job).await?;
+    println!("cancelled job {}", args.job);
+    Ok(())
+}
+
+async fn run(args: RunArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let spec = args.job.job_spec()?;
+    log::info!("Submitting job:\n{}", spec.to_toml());
+
+    let config = ServerConfig {
+        addr: args.bind,
+        health_check_interval: Duration::from_secs(spec.health_check_interval_secs),
+        worker_lease: Duration::from_secs(args.worker_lease),
+    };
+    if spec.iterate.is_some() {
+        let registry = Arc::new(Mutex::new(JobRegistry::new()));
+        let server = mapreduce::server::serve(Arc::clone(&registry), Arc::default(), config);
+        tokio::pin!(server);
+        tokio::select! {
+            result = &mut server => return result,
+            result = iterate::run_iterations(spec, registry) => {
+                let n = result.map_err(|e| e as Box<dyn std::error::Error>)?;
+                log::info!("Iterative job finished after {} iterations", n);
+            }
+        }
+        return server.await;
+    }
+    let master = Master::from_spec(spec)?;
+    mapreduce::server::run_server(master, config).await
+}
+
+async fn run_pipeline(args: PipelineArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let spec = PipelineSpec::from_file(&args.spec)?;
+    log::info!(
+        "Running pipeline '{}' with {} stages",
+        spec.display_name(),
+        spec.stages.len()
+    );
 
-    let input_files = (1..=5).map(|i| format!("input/file{i}.txt")).collect();
-    let n_reduce: u32 = 5;
-    let output_path = "output".to_string();
-    let addr = format!("127.0.0.1:{}", port);
+    let interval = spec
+        .stages
+        .iter()
+        .map(|s| s.health_check_interval_secs)
+        .min()
+        .unwrap_or(10);
+    let config = ServerConfig {
+        addr: args.bind,
+        health_check_interval: Duration::from_secs(interval),
+        worker_lease: Duration::from_secs(args.worker_lease),
+    };
+    let registry = Arc::new(Mutex::new(JobRegistry::new()));
+    let stages = Arc::new(Mutex::new(spec.statuses()));
 
-    mapreduce::server::run_server(input_files, n_reduce, output_path, addr).await
+    let server = mapreduce::server::serve(Arc::clone(&registry), Arc::clone(&stages), config);
+    tokio::pin!(server);
+    tokio::select! {
+        result = &mut server => return result,
+        result = pipeline::run_pipeline(spec, registry, stages, args.resume) => {
+            result.map_err(|e| e as Box<dyn std::error::Error>)?
+        }
+    }
+    log::info!("Pipeline complete");
+    server.await
 }
@@ -1,74 +1,282 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
+use clap::{Parser, Subcommand};
+use mapreduce::cache;
 use mapreduce::client::{Client, TaskType};
-use mapreduce::models::Report;
-use mapreduce::rpc::{TaskData, TaskType as RpcTaskType};
+use mapreduce::models::{Progress, Report};
+use mapreduce::rpc::{AttemptRef, TaskProgress, TaskType as RpcTaskType};
 use mapreduce::worker::Worker;
 
+/// MapReduce worker: pulls map and reduce tasks from a master and executes them.
+#[derive(Parser)]
+#[command(name = "worker", version)]
+struct Cli {
+    /// Log level (error, warn, info, debug, trace)
+    #[arg(long, global = true, default_value = "info")]
+    log_level: log::LevelFilter,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Connect to a master and run tasks until the job is done
+    Run(RunArgs),
+}
+
+#[derive(clap::Args)]
+struct RunArgs {
+    /// Master address
+    #[arg(short, long, default_value = "http://127.0.0.1:50051")]
+    master: String,
+
+    /// Number of tasks to run concurrently
+    #[arg(short, long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    slots: u32,
+
+    /// Directory for side files fetched from the master's distributed cache
+    #[arg(long, default_value_os_t = std::env::temp_dir().join("mapreduce-cache"))]
+    cache_dir: PathBuf,
+
+    /// Name to register with the master, shown in task attempts
+    #[arg(long, default_value_t = default_name())]
+    name: String,
+}
+
+/// How long the master may hold a task request open while it has nothing to hand out.
+const TASK_WAIT: Duration = Duration::from_secs(10);
+
+/// Attempts running in this worker's slots, keyed by slot, for heartbeats to report.
+type Running = Arc<Mutex<BTreeMap<u32, (AttemptRef, Arc<Progress>)>>>;
+
+fn default_name() -> String {
+    let host = std::env::var("HOSTNAME").unwrap_or_else(|_| "worker".to_string());
+    format!("{}:{}", host, std::process::id())
+}
+
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let cli = Cli::parse();
     env_logger::Builder::new()
-        .filter_level(log::LevelFilter::Info)
+        .filter_level(cli.log_level)
         .init();
 
-    let args: Vec<String> = std::env::args().collect();
-    let addr = args.get(1).map(|s| s.as_str()).unwrap_or("http://127.0.0.1:50051");
+    match cli.command {
+        Command::Run(args) => run(args).await,
+    }
+}
+
+async fn run(args: RunArgs) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    log::info!(
+        "Worker connecting to {} with {} slots",
+        args.master,
+        args.slots
+    );
+
+    let mut client = Client::connect(&args.master).await?;
+    let (id, interval) = client.register_worker(&args.name, args.slots).await?;
+    log::info!("Registered as worker {} ({})", id, args.name);
+    let worker_id = Arc::new(AtomicU32::new(id));
+    let running = Running::default();
+    let heartbeat = tokio::spawn(send_heartbeats(
+        client,
+        args.name.clone(),
+        args.slots,
+        Arc::clone(&worker_id),
+        Arc::clone(&running),
+        interval,
+    ));
+
+    let mut slots = tokio::task::JoinSet::new();
+    for slot in 0..args.slots {
+        slots.spawn(run_slot(
+            args.master.clone(),
+            args.cache_dir.clone(),
+            Arc::clone(&worker_id),
+            Arc::clone(&running),
+            slot,
+        ));
+    }
+    while let Some(result) = slots.join_next().await {
+        result??;
+    }
+    heartbeat.abort();
 
-    log::info!("Worker connecting to {}", addr);
+    Ok(())
+}
 
-    let mut client = Client::connect(addr).await?;
+/// Renews the worker's lease every `interval`, reporting the progress of running attempts
+/// and aborting those the master no longer wants. Registers again if the master has
+/// expired the worker.
+async fn send_heartbeats(
+    mut client: Client,
+    name: String,
+    slots: u32,
+    worker_id: Arc<AtomicU32>,
+    running: Running,
+    interval: Duration,
+) {
+    loop {
+        tokio::time::sleep(interval).await;
+        let progress: Vec<TaskProgress> = running
+            .lock()
+            .unwrap()
+            .values()
+            .map(|(attempt, progress)| TaskProgress {
+                attempt: attempt.clone(),
+                fraction: progress.get(),
+            })
+            .collect();
+        match client
+            .heartbeat(worker_id.load(Ordering::Relaxed), &progress)
+            .await
+        {
+            Ok(Some(abort)) => {
+                for (attempt, progress) in running.lock().unwrap().values() {
+                    if abort.contains(attempt) && !progress.is_aborted() {
+                        log::info!(
+                            "Master aborted {} task {} attempt {}",
+                            attempt.task_type,
+                            attempt.task_id,
+                            attempt.attempt_id
+                        );
+                        progress.abort();
+                    }
+                }
+            }
+            Ok(None) => match client.register_worker(&name, slots).await {
+                Ok((id, _)) => {
+                    log::warn!("Master expired this worker, registered again as {}", id);
+                    worker_id.store(id, Ordering::Relaxed);
+                }
+                Err(e) => log::warn!("Failed to register again: {}", e),
+            },
+            Err(e) => log::warn!("Heartbeat failed: {}", e),
+        }
+    }
+}
+
+async fn run_slot(
+    addr: String,
+    cache_dir: PathBuf,
+    worker_id: Arc<AtomicU32>,
+    running: Running,
+    slot: u32,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut client = Client::connect(&addr).await?;
 
     loop {
-        log::info!("Asking for task...");
-        let task = client.get_task().await?;
+        log::info!("[slot {}] Asking for task...", slot);
+        let task = client
+            .get_task(worker_id.load(Ordering::Relaxed), TASK_WAIT)
+            .await?;
 
         match task {
             TaskType::Exit => {
-                log::info!("Received Exit, shutting down");
+                log::info!("[slot {}] Received Exit, shutting down", slot);
                 break;
             }
             TaskType::Idle => {
-                log::debug!("No task available, sleeping...");
-                tokio::time::sleep(Duration::from_secs(2)).await;
+                log::debug!("[slot {}] No task available, asking again", slot);
             }
-            TaskType::Map {
-                task_id,
-                input_files,
-                n_reduce,
-                output_path,
-            } => {
-                let task_data = TaskData {
-                    task_id,
-                    input_files,
-                    n_reduce,
-                    output_path,
+            TaskType::Map(task_data) => {
+                let (job_id, attempt_id) = (task_data.job_id, task_data.attempt_id);
+                let attempt = task_data.attempt(RpcTaskType::Map);
+                let cache_files =
+                    match cache::localize(&mut client, &task_data.cache_files, &cache_dir).await {
+                        Ok(files) => files,
+                        Err(e) => {
+                            report_failure(&mut client, &attempt, slot, e).await?;
+                            continue;
+                        }
+                    };
+                let mut worker = Worker::new(task_data, RpcTaskType::Map);
+                worker.cache_files = cache_files;
+                let worker = Arc::new(worker);
+                let report = match run_task(&worker, attempt.clone(), &running, slot).await {
+                    Ok(report) => report,
+                    Err(e) => {
+                        report_failure(&mut client, &attempt, slot, e).await?;
+                        continue;
+                    }
                 };
-                let worker = Worker::new(task_data, RpcTaskType::Map);
-                let report = worker.run();
 
-                if let Report::MapDone { taskid, files } = report {
-                    log::info!("Map task {} complete, sending MapDone...", taskid);
-                    client.map_done(taskid, files).await?;
+                if let Report::MapDone {
+                    taskid,
+                    files,
+                    counters,
+                    skipped,
+                } = &report
+                {
+                    log::info!(
+                        "[slot {}] Map task {} complete, sending MapDone...",
+                        slot,
+                        taskid
+                    );
+                    let abort = client
+                        .map_done(
+                            job_id,
+                            *taskid,
+                            attempt_id,
+                            files.clone(),
+                            counters.clone(),
+                            skipped,
+                        )
+                        .await?;
+                    if abort {
+                        log::info!(
+                            "[slot {}] Job {} was cancelled, discarding output",
+                            slot,
+                            job_id
+                        );
+                        discard(worker, report).await?;
+                    }
                 }
             }
-            TaskType::Reduce {
-                task_id,
-                input_files,
-                n_reduce,
-                output_path,
-            } => {
-                let task_data = TaskData {
-                    task_id,
-                    input_files,
-                    n_reduce,
-                    output_path,
+            TaskType::Reduce(task_data) => {
+                let (job_id, attempt_id) = (task_data.job_id, task_data.attempt_id);
+                let attempt = task_data.attempt(RpcTaskType::Reduce);
+                let cache_files =
+                    match cache::localize(&mut client, &task_data.cache_files, &cache_dir).await {
+                        Ok(files) => files,
+                        Err(e) => {
+                            report_failure(&mut client, &attempt, slot, e).await?;
+                            continue;
+                        }
+                    };
+                let mut worker = Worker::new(task_data, RpcTaskType::Reduce);
+                worker.cache_files = cache_files;
+                let worker = Arc::new(worker);
+                let report = match run_task(&worker, attempt.clone(), &running, slot).await {
+                    Ok(report) => report,
+                    Err(e) => {
+                        report_failure(&mut client, &attempt, slot, e).await?;
+                        continue;
+                    }
                 };
-                let worker = Worker::new(task_data, RpcTaskType::Reduce);
-                let report = worker.run();
 
-                if let Report::ReducerDone { taskid } = report {
-                    log::info!("Reduce task {} complete, sending ReduceDone...", taskid);
-                    client.reduce_done(taskid).await?;
+                if let Report::ReducerDone { taskid, counters } = &report {
+                    log::info!(
+                        "[slot {}] Reduce task {} complete, sending ReduceDone...",
+                        slot,
+                        taskid
+                    );
+                    let abort = client
+                        .reduce_done(job_id, *taskid, attempt_id, counters.clone())
+                        .await?;
+                    if abort {
+                        log::info!(
+                            "[slot {}] Job {} was cancelled, discarding output",
+                            slot,
+                            job_id
+                        );
+                        discard(worker, report).await?;
+                    }
                 }
             }
         }
@@ -76,3 +284,46 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+/// Runs a task on the blocking thread pool while listing it in `running` for heartbeats.
+/// The runtime stays free for heartbeats and the other slots while the task runs.
+async fn run_task(
+    worker: &Arc<Worker>,
+    attempt: AttemptRef,
+    running: &Running,
+    slot: u32,
+) -> Result<Report, Box<dyn std::error::Error + Send + Sync>> {
+    running
+        .lock()
+        .unwrap()
+        .insert(slot, (attempt, Arc::clone(&worker.progress)));
+    let task = Arc::clone(worker);
+    let report = tokio::task::spawn_blocking(move || task.run()).await;
+    running.lock().unwrap().remove(&slot);
+    report?
+}
+
+/// Removes the output of an attempt the master did not accept.
+async fn discard(
+    worker: Arc<Worker>,
+    report: Report,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    tokio::task::spawn_blocking(move || worker.discard(&report)).await?;
+    Ok(())
+}
+
+async fn report_failure(
+    client: &mut Client,
+    attempt: &AttemptRef,
+    slot: u32,
+    error: Box<dyn std::error::Error + Send + Sync>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    log::error!(
+        "[slot {}] {} task {} failed: {}",
+        slot,
+        attempt.task_type,
+        attempt.task_id,
+        error
+    );
+    client.report_failure(attempt, &error.to_string()).await
+}
@@ -0,0 +1,314 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+
+use crate::models::{
+    ConvergeFunction, Iteration, JobContext, JoinFunction, KeyValue, MapFunction, ReduceFunction,
+};
+
+/// A named map/reduce application that jobs refer to by `app` name.
+pub struct App {
+    pub name: &'static str,
+    pub map: MapFunction, // also the default mapper for datasets of a join
+    pub combine: Option<ReduceFunction>, // applied to each map task's output before partitioning
+    pub reduce: Reducer,
+    pub required_config: &'static [&'static str],
+}
+
+pub enum Reducer {
+    Values(ReduceFunction),
+    Tagged(JoinFunction), // for jobs over several tagged datasets
+}
+
+/// A named map function that join datasets can select with `mapper`.
+pub struct Mapper {
+    pub name: &'static str,
+    pub map: MapFunction,
+}
+
+static MAPPERS: &[Mapper] = &[
+    Mapper {
+        name: "tsv",
+        map: tsv_map,
+    },
+    Mapper {
+        name: "csv",
+        map: csv_map,
+    },
+];
+
+/// A named convergence check that iterative jobs select with `iterate.until`.
+pub struct Convergence {
+    pub name: &'static str,
+    pub converged: ConvergeFunction,
+}
+
+static CONVERGENCE: &[Convergence] = &[
+    Convergence {
+        name: "counter_zero",
+        converged: counter_zero,
+    },
+    Convergence {
+        name: "counter_below",
+        converged: counter_below,
+    },
+    Convergence {
+        name: "counter_stable",
+        converged: counter_stable,
+    },
+    Convergence {
+        name: "output_stable",
+        converged: output_stable,
+    },
+];
+
+static APPS: &[App] = &[
+    App {
+        name: "wc",
+        map: wc_map,
+        combine: Some(sum_reduce),
+        reduce: Reducer::Values(sum_reduce),
+        required_config: &[],
+    },
+    App {
+        name: "grep",
+        map: grep_map,
+        combine: Some(sum_reduce),
+        reduce: Reducer::Values(sum_reduce),
+        required_config: &["pattern"],
+    },
+    App {
+        name: "join",
+        map: tsv_map,
+        combine: None,
+        reduce: Reducer::Tagged(inner_join),
+        required_config: &[],
+    },
+    App {
+        name: "pagerank",
+        map: pagerank_map,
+        combine: None,
+        reduce: Reducer::Values(pagerank_reduce),
+        required_config: &[],
+    },
+];
+
+pub fn lookup(name: &str) -> Option<&'static App> {
+    APPS.iter().find(|app| app.name == name)
+}
+
+pub fn names() -> Vec<&'static str> {
+    APPS.iter().map(|app| app.name).collect()
+}
+
+/// Finds a map function by mapper name, falling back to the map function of an app.
+pub fn mapper(name: &str) -> Option<MapFunction> {
+    MAPPERS
+        .iter()
+        .find(|m| m.name == name)
+        .map(|m| m.map)
+        .or_else(|| lookup(name).map(|app| app.map))
+}
+
+pub fn convergence(name: &str) -> Option<ConvergeFunction> {
+    CONVERGENCE
+        .iter()
+        .find(|c| c.name == name)
+        .map(|c| c.converged)
+}
+
+pub fn convergence_names() -> Vec<&'static str> {
+    CONVERGENCE.iter().map(|c| c.name).collect()
+}
+
+/// Counts words. If the `stopwords` config key names a cache file, words listed in
+/// it (whitespace separated) are skipped.
+fn wc_map(ctx: &JobContext, _filename: String, content: String) -> Vec<KeyValue> {
+    let stopwords: HashSet<String> = ctx
+        .get("stopwords")
+        .and_then(|name| ctx.cache_path(name))
+        .and_then(|path| fs::read_to_string(path).ok())
+        .map(|s| s.split_whitespace().map(str::to_string).collect())
+        .unwrap_or_default();
+    let kvs: Vec<KeyValue> = content
+        .split_whitespace()
+        .filter(|word| !stopwords.contains(*word))
+        .map(|word| KeyValue {
+            key: word.to_string(),
+            value: "1".to_string(),
+        })
+        .collect();
+    ctx.incr("words", kvs.len() as i64);
+    kvs
+}
+
+/// Emits every line containing the `pattern` config value, counted by `sum_reduce`.
+fn grep_map(ctx: &JobContext, _filename: String, content: String) -> Vec<KeyValue> {
+    let pattern = ctx.get("pattern").unwrap_or_default();
+    let mut kvs = Vec::new();
+    for line in content.lines() {
+        ctx.incr("lines_scanned", 1);
+        if line.contains(pattern) {
+            kvs.push(KeyValue {
+                key: line.to_string(),
+                value: "1".to_string(),
+            });
+        }
+    }
+    ctx.incr("lines_matched", kvs.len() as i64);
+    kvs
+}
+
+fn sum_reduce(_ctx: &JobContext, _key: String, values: Vec<String>) -> String {
+    let val = values.iter().map(|v| v.parse().unwrap_or(0)).sum::<u32>();
+    val.to_string()
+}
+
+fn split_map(content: &str, delimiter: char) -> Vec<KeyValue> {
+    content
+        .lines()
+        .filter_map(|line| line.split_once(delimiter))
+        .map(|(key, value)| KeyValue {
+            key: key.to_string(),
+            value: value.to_string(),
+        })
+        .collect()
+}
+
+/// Keys each line by its first tab-separated field; the rest of the line is the value.
+fn tsv_map(_ctx: &JobContext, _filename: String, content: String) -> Vec<KeyValue> {
+    split_map(&content, '\t')
+}
+
+/// Keys each line by its first comma-separated field; the rest of the line is the value.
+fn csv_map(_ctx: &JobContext, _filename: String, content: String) -> Vec<KeyValue> {
+    split_map(&content, ',')
+}
+
+/// Inner join: one tab-separated output value per combination of values across all
+/// datasets, in tag order. Keys missing from any dataset produce nothing.
+fn inner_join(
+    ctx: &JobContext,
+    _key: String,
+    by_tag: BTreeMap<String, Vec<String>>,
+) -> Vec<String> {
+    if by_tag.len() < ctx.datasets.len() {
+        return Vec::new();
+    }
+    let mut rows = vec![String::new()];
+    for values in by_tag.values() {
+        rows = rows
+            .iter()
+            .flat_map(|row| {
+                values.iter().map(move |v| {
+                    if row.is_empty() {
+                        v.clone()
+                    } else {
+                        format!("{}\t{}", row, v)
+                    }
+                })
+            })
+            .collect();
+    }
+    rows
+}
+
+/// One PageRank step over lines of `node rank neighbour,neighbour,...` (rank defaults
+/// to 1). Each node sends its rank, split evenly, to its neighbours, and itself its
+/// link list and previous rank.
+fn pagerank_map(_ctx: &JobContext, _filename: String, content: String) -> Vec<KeyValue> {
+    let mut kvs = Vec::new();
+    for line in content.lines() {
+        let mut fields = line.split_whitespace();
+        let Some(node) = fields.next() else {
+            continue;
+        };
+        let rank: f64 = fields.next().and_then(|r| r.parse().ok()).unwrap_or(1.0);
+        let links: Vec<&str> = fields
+            .next()
+            .map(|l| l.split(',').filter(|n| !n.is_empty()).collect())
+            .unwrap_or_default();
+        for link in &links {
+            kvs.push(KeyValue {
+                key: link.to_string(),
+                value: format!("rank:{}", rank / links.len() as f64),
+            });
+        }
+        kvs.push(KeyValue {
+            key: node.to_string(),
+            value: format!("links:{}", links.join(",")),
+        });
+        kvs.push(KeyValue {
+            key: node.to_string(),
+            value: format!("prev:{}", rank),
+        });
+    }
+    kvs
+}
+
+/// Damped sum of a node's incoming rank. Counts the total rank change in millionths as
+/// `rank_delta_micros` for convergence checks.
+fn pagerank_reduce(ctx: &JobContext, _key: String, values: Vec<String>) -> String {
+    let mut sum = 0.0;
+    let mut prev = 1.0;
+    let mut links = "";
+    for value in &values {
+        if let Some(r) = value.strip_prefix("rank:") {
+            sum += r.parse::<f64>().unwrap_or(0.0);
+        } else if let Some(r) = value.strip_prefix("prev:") {
+            prev = r.parse().unwrap_or(1.0);
+        } else if let Some(l) = value.strip_prefix("links:") {
+            links = l;
+        }
+    }
+    let rank = 0.15 + 0.85 * sum;
+    ctx.incr("rank_delta_micros", ((rank - prev).abs() * 1e6) as i64);
+    if links.is_empty() {
+        format!("{:.6}", rank)
+    } else {
+        format!("{:.6}\t{}", rank, links)
+    }
+}
+
+fn counter_value(it: &Iteration) -> i64 {
+    let name = it.spec.counter.as_deref().unwrap_or_default();
+    it.counters.get(name).copied().unwrap_or(0)
+}
+
+/// Converged once the counter is zero (or was never incremented).
+fn counter_zero(it: &Iteration) -> bool {
+    counter_value(it) == 0
+}
+
+/// Converged once the counter drops below `threshold`.
+fn counter_below(it: &Iteration) -> bool {
+    counter_value(it) < it.spec.threshold.unwrap_or(0)
+}
+
+/// Converged once the counter has the same value as in the previous iteration.
+fn counter_stable(it: &Iteration) -> bool {
+    let name = it.spec.counter.as_deref().unwrap_or_default();
+    it.previous
+        .is_some_and(|prev| prev.get(name).copied().unwrap_or(0) == counter_value(it))
+}
+
+/// Converged once an iteration's output is identical to the previous iteration's.
+fn output_stable(it: &Iteration) -> bool {
+    let read = |dir: &str| -> Option<Vec<(String, Vec<u8>)>> {
+        let mut files: Vec<(String, Vec<u8>)> = fs::read_dir(dir)
+            .ok()?
+            .filter_map(Result::ok)
+            .map(|e| e.file_name().to_string_lossy().to_string())
+            .filter(|name| name.starts_with("mr-out-") && !name.ends_with(".tmp"))
+            .map(|name| {
+                let content = fs::read(format!("{}/{}", dir, name)).unwrap_or_default();
+                (name, content)
+            })
+            .collect();
+        files.sort();
+        Some(files)
+    };
+    match it.previous_output_dir {
+        Some(prev) => read(prev).is_some() && read(prev) == read(it.output_dir),
+        None => false,
+    }
+}
@@ -0,0 +1,53 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use sha2::{Digest, Sha256};
+use tokio::fs;
+
+use crate::client::Client;
+use crate::rpc::CacheFile;
+
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Hex-encoded SHA-256 of `content`, used to address cached side files.
+pub fn content_hash(content: &[u8]) -> String {
+    Sha256::digest(content)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Makes the job's side files available under `dir`, fetching from the master only
+/// the ones not already cached. Files are stored by content hash, so each is
+/// downloaded once per worker no matter how many tasks use it.
+pub async fn localize(
+    client: &mut Client,
+    files: &[CacheFile],
+    dir: &Path,
+) -> Result<BTreeMap<String, PathBuf>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut paths = BTreeMap::new();
+    for file in files {
+        let local = dir.join(&file.hash).join(&file.name);
+        if !fs::try_exists(&local).await? {
+            log::info!("Fetching cache file {} ({})", file.name, file.hash);
+            let content = client.fetch_cache_file(&file.hash).await?;
+            if content_hash(&content) != file.hash {
+                return Err(format!("cache file {} failed hash verification", file.name).into());
+            }
+            let parent = local.parent().expect("cache path has a parent");
+            fs::create_dir_all(parent).await?;
+            let unique = TEMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+            let temp = parent.join(format!(
+                "{}.{}.{}.tmp",
+                file.name,
+                std::process::id(),
+                unique
+            ));
+            fs::write(&temp, content).await?;
+            fs::rename(&temp, &local).await?;
+        }
+        paths.insert(file.name.clone(), local);
+    }
+    Ok(paths)
+}
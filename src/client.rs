@@ -1,7 +1,11 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
 
 use tonic::transport::Channel;
 
+use crate::job::JobSpec;
+use crate::rpc::{AttemptRef, CacheFile, TaskData, TaskProgress, TaskType as RpcTaskType};
+
 pub mod mr {
     tonic::include_proto!("mapreduce");
 }
@@ -11,27 +15,124 @@ pub struct Client {
 }
 
 impl Client {
-    pub async fn connect(addr: &str) -> Result<Self, Box<dyn std::error::Error>> {
+    pub async fn connect(addr: &str) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
         let client = mr::map_reduce_client::MapReduceClient::connect(addr.to_string()).await?;
         Ok(Client { inner: client })
     }
 
-    pub async fn get_task(&mut self) -> Result<TaskType, Box<dyn std::error::Error>> {
-        let response = self.inner.get_task(mr::Empty {}).await?.into_inner();
+    /// Registers with the master, advertising `slots` task slots. Returns the worker id
+    /// and the heartbeat interval.
+    pub async fn register_worker(
+        &mut self,
+        name: &str,
+        slots: u32,
+    ) -> Result<(u32, Duration), Box<dyn std::error::Error + Send + Sync>> {
+        let request = mr::RegisterWorkerRequest {
+            name: name.to_string(),
+            slots,
+        };
+        let response = self.inner.register_worker(request).await?.into_inner();
+        Ok((
+            response.worker_id,
+            Duration::from_millis(response.heartbeat_interval_ms),
+        ))
+    }
 
-        let task_type = match response.task_type.as_str() {
-            "map" => TaskType::Map {
-                task_id: response.task_id,
-                input_files: response.input_files,
-                n_reduce: response.n_reduce,
-                output_path: response.output_path,
-            },
-            "reduce" => TaskType::Reduce {
+    /// Renews the worker's lease and reports the progress of its running attempts.
+    /// Returns the attempts the master wants stopped, or None if the master no longer
+    /// knows the worker.
+    pub async fn heartbeat(
+        &mut self,
+        worker_id: u32,
+        progress: &[TaskProgress],
+    ) -> Result<Option<Vec<AttemptRef>>, Box<dyn std::error::Error + Send + Sync>> {
+        let tasks = progress
+            .iter()
+            .map(|p| mr::TaskProgress {
+                attempt: Some(proto_attempt(&p.attempt)),
+                fraction: p.fraction,
+            })
+            .collect();
+        let request = mr::HeartbeatRequest { worker_id, tasks };
+        let response = self.inner.heartbeat(request).await?.into_inner();
+        if !response.registered {
+            return Ok(None);
+        }
+        let abort = response
+            .abort
+            .into_iter()
+            .map(|a| AttemptRef {
+                job_id: a.job_id,
+                task_type: match a.task_type.as_str() {
+                    "reduce" => RpcTaskType::Reduce,
+                    _ => RpcTaskType::Map,
+                },
+                task_id: a.task_id,
+                attempt_id: a.attempt_id,
+            })
+            .collect();
+        Ok(Some(abort))
+    }
+
+    /// Tells the master an attempt failed, so it can retry the task elsewhere.
+    pub async fn report_failure(
+        &mut self,
+        attempt: &AttemptRef,
+        error: &str,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let request = mr::ReportFailureRequest {
+            attempt: Some(proto_attempt(attempt)),
+            error: error.to_string(),
+        };
+        self.inner.report_failure(request).await?;
+        Ok(())
+    }
+
+    /// Asks for a task, letting the master hold the call for up to `wait` until one is
+    /// available.
+    pub async fn get_task(
+        &mut self,
+        worker_id: u32,
+        wait: Duration,
+    ) -> Result<TaskType, Box<dyn std::error::Error + Send + Sync>> {
+        let request = mr::GetTaskRequest {
+            worker_id,
+            wait_ms: wait.as_millis() as u64,
+        };
+        let response = self.inner.get_task(request).await?.into_inner();
+
+        let task_data = || -> Result<TaskData, Box<dyn std::error::Error + Send + Sync>> {
+            Ok(TaskData {
+                job_id: response.job_id,
                 task_id: response.task_id,
-                input_files: response.input_files,
+                input_files: response.input_files.clone(),
                 n_reduce: response.n_reduce,
-                output_path: response.output_path,
-            },
+                output_path: response.output_path.clone(),
+                row_group: response.row_group,
+                input_format: response.input_format.parse()?,
+                output_format: response.output_format.parse()?,
+                app: response.app.clone(),
+                partitioner: response.partitioner.parse()?,
+                attempt_id: response.attempt_id,
+                config: response.config.clone().into_iter().collect(),
+                cache_files: response
+                    .cache_files
+                    .iter()
+                    .map(|f| CacheFile {
+                        name: f.name.clone(),
+                        hash: f.hash.clone(),
+                    })
+                    .collect(),
+                tag: response.tag.clone(),
+                mapper: response.mapper.clone(),
+                datasets: response.datasets.clone(),
+                skip_bad_records: response.skip_bad_records,
+            })
+        };
+
+        let task_type = match response.task_type.as_str() {
+            "map" => TaskType::Map(task_data()?),
+            "reduce" => TaskType::Reduce(task_data()?),
             "idle" => TaskType::Idle,
             "exit" => TaskType::Exit,
             _ => TaskType::Idle,
@@ -40,39 +141,121 @@ impl Client {
         Ok(task_type)
     }
 
+    /// Reports a finished map task. Returns true if the task's job was cancelled and its
+    /// output should be discarded.
     pub async fn map_done(
         &mut self,
+        job_id: u32,
         task_id: u32,
+        attempt_id: u32,
         files: HashMap<u32, String>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+        counters: BTreeMap<String, i64>,
+        skipped: &[(u64, u64)],
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
         let request = mr::MapDoneRequest {
+            job_id,
             task_id,
+            attempt_id,
             files: files.into_iter().collect(),
+            counters: counters.into_iter().collect(),
+            skipped: skipped
+                .iter()
+                .map(|&(start, end)| mr::RecordRange { start, end })
+                .collect(),
         };
-        self.inner.map_done(request).await?;
-        Ok(())
+        Ok(self.inner.map_done(request).await?.into_inner().abort)
     }
 
-    pub async fn reduce_done(&mut self, task_id: u32) -> Result<(), Box<dyn std::error::Error>> {
-        let request = mr::ReduceDoneRequest { task_id };
-        self.inner.reduce_done(request).await?;
+    /// Reports a finished reduce task; returns true like `map_done` if it must be discarded.
+    pub async fn reduce_done(
+        &mut self,
+        job_id: u32,
+        task_id: u32,
+        attempt_id: u32,
+        counters: BTreeMap<String, i64>,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let request = mr::ReduceDoneRequest {
+            job_id,
+            task_id,
+            attempt_id,
+            counters: counters.into_iter().collect(),
+        };
+        Ok(self.inner.reduce_done(request).await?.into_inner().abort)
+    }
+
+    pub async fn fetch_cache_file(
+        &mut self,
+        hash: &str,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+        let request = mr::CacheFileRequest {
+            hash: hash.to_string(),
+        };
+        Ok(self
+            .inner
+            .fetch_cache_file(request)
+            .await?
+            .into_inner()
+            .content)
+    }
+
+    /// Status of a job, or of the most recently submitted job.
+    pub async fn job_status(
+        &mut self,
+        job_id: Option<u32>,
+    ) -> Result<mr::JobStatusResponse, Box<dyn std::error::Error + Send + Sync>> {
+        let request = mr::JobStatusRequest { job_id };
+        Ok(self.inner.get_job_status(request).await?.into_inner())
+    }
+
+    pub async fn submit_job(
+        &mut self,
+        spec: &JobSpec,
+    ) -> Result<u32, Box<dyn std::error::Error + Send + Sync>> {
+        let request = mr::SubmitJobRequest {
+            spec: spec.to_toml(),
+        };
+        Ok(self.inner.submit_job(request).await?.into_inner().job_id)
+    }
+
+    pub async fn list_jobs(
+        &mut self,
+    ) -> Result<Vec<mr::JobSummary>, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self.inner.list_jobs(mr::Empty {}).await?.into_inner().jobs)
+    }
+
+    pub async fn list_workers(
+        &mut self,
+    ) -> Result<Vec<mr::WorkerSummary>, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self
+            .inner
+            .list_workers(mr::Empty {})
+            .await?
+            .into_inner()
+            .workers)
+    }
+
+    pub async fn cancel_job(
+        &mut self,
+        job_id: u32,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let request = mr::CancelJobRequest { job_id };
+        self.inner.cancel_job(request).await?;
         Ok(())
     }
 }
 
+fn proto_attempt(attempt: &AttemptRef) -> mr::AttemptRef {
+    mr::AttemptRef {
+        job_id: attempt.job_id,
+        task_type: attempt.task_type.to_string(),
+        task_id: attempt.task_id,
+        attempt_id: attempt.attempt_id,
+    }
+}
+
 pub enum TaskType {
-    Map {
-        task_id: u32,
-        input_files: Vec<String>,
-        n_reduce: u32,
-        output_path: String,
-    },
-    Reduce {
-        task_id: u32,
-        input_files: Vec<String>,
-        n_reduce: u32,
-        output_path: String,
-    },
+    Map(TaskData),
+    Reduce(TaskData),
     Idle,
     Exit,
 }
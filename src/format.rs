@@ -0,0 +1,284 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use arrow_array::{ArrayRef, Float64Array, Int64Array, RecordBatch, StringArray};
+use arrow_cast::display::{ArrayFormatter, FormatOptions};
+use arrow_schema::{DataType, Field, Schema};
+use parquet::arrow::ArrowWriter;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use serde::{Deserialize, Serialize};
+
+/// Type of the value column in typed (columnar) output.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub enum ValueType {
+    Utf8,
+    Int64,
+    Float64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(try_from = "String", into = "String")]
+pub enum InputFormat {
+    #[default]
+    Text,
+    Parquet,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(try_from = "String", into = "String")]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Parquet(ValueType),
+}
+
+/// A unit of map input: a whole file, or a single row group of a Parquet file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct InputSplit {
+    pub path: String,
+    pub row_group: Option<u32>,
+    pub tag: Option<String>, // input dataset the split belongs to
+}
+
+impl ValueType {
+    fn data_type(self) -> DataType {
+        match self {
+            ValueType::Utf8 => DataType::Utf8,
+            ValueType::Int64 => DataType::Int64,
+            ValueType::Float64 => DataType::Float64,
+        }
+    }
+}
+
+impl OutputFormat {
+    /// Name of the committed output file for a reduce partition.
+    pub fn output_file(&self, output_path: &str, partition: u32) -> String {
+        match self {
+            OutputFormat::Text => format!("{}/mr-out-{}", output_path, partition),
+            OutputFormat::Parquet(_) => format!("{}/mr-out-{}.parquet", output_path, partition),
+        }
+    }
+
+    pub fn schema(&self) -> Option<Schema> {
+        match self {
+            OutputFormat::Text => None,
+            OutputFormat::Parquet(value_type) => Some(Schema::new(vec![
+                Field::new("key", DataType::Utf8, false),
+                Field::new("value", value_type.data_type(), false),
+            ])),
+        }
+    }
+}
+
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValueType::Utf8 => write!(f, "utf8"),
+            ValueType::Int64 => write!(f, "int64"),
+            ValueType::Float64 => write!(f, "float64"),
+        }
+    }
+}
+
+impl FromStr for ValueType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "utf8" | "string" => Ok(ValueType::Utf8),
+            "int64" => Ok(ValueType::Int64),
+            "float64" => Ok(ValueType::Float64),
+            _ => Err(format!("unknown value type '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for InputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InputFormat::Text => write!(f, "text"),
+            InputFormat::Parquet => write!(f, "parquet"),
+        }
+    }
+}
+
+impl FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(InputFormat::Text),
+            "parquet" => Ok(InputFormat::Parquet),
+            _ => Err(format!("unknown input format '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputFormat::Text => write!(f, "text"),
+            OutputFormat::Parquet(value_type) => write!(f, "parquet:{}", value_type),
+        }
+    }
+}
+
+/// Parses `text`, `parquet` (utf8 values) or `parquet:<value type>`.
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "text" => Ok(OutputFormat::Text),
+            None if s == "parquet" => Ok(OutputFormat::Parquet(ValueType::Utf8)),
+            Some(("parquet", value_type)) => Ok(OutputFormat::Parquet(value_type.parse()?)),
+            _ => Err(format!("unknown output format '{}'", s)),
+        }
+    }
+}
+
+macro_rules! string_serde {
+    ($t:ty) => {
+        impl TryFrom<String> for $t {
+            type Error = String;
+
+            fn try_from(s: String) -> Result<Self, Self::Error> {
+                s.parse()
+            }
+        }
+
+        impl From<$t> for String {
+            fn from(v: $t) -> String {
+                v.to_string()
+            }
+        }
+    };
+}
+
+string_serde!(ValueType);
+string_serde!(InputFormat);
+string_serde!(OutputFormat);
+
+/// Splits input files into map splits: one per text file, one per Parquet row group.
+pub fn plan_splits(
+    input_files: &[String],
+    format: InputFormat,
+) -> Result<Vec<InputSplit>, Box<dyn std::error::Error>> {
+    let mut splits = Vec::new();
+    for path in input_files {
+        match format {
+            InputFormat::Text => splits.push(InputSplit {
+                path: path.clone(),
+                row_group: None,
+                tag: None,
+            }),
+            InputFormat::Parquet => {
+                let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(path)?)?;
+                for row_group in 0..builder.metadata().num_row_groups() {
+                    splits.push(InputSplit {
+                        path: path.clone(),
+                        row_group: Some(row_group as u32),
+                        tag: None,
+                    });
+                }
+            }
+        }
+    }
+    Ok(splits)
+}
+
+/// Reads a split as text. Parquet rows become one line each, columns separated by tabs.
+pub fn read_split(
+    split: &InputSplit,
+    format: InputFormat,
+) -> Result<String, Box<dyn std::error::Error>> {
+    match format {
+        InputFormat::Text => Ok(fs::read_to_string(&split.path)?),
+        InputFormat::Parquet => {
+            let mut builder = ParquetRecordBatchReaderBuilder::try_new(File::open(&split.path)?)?;
+            if let Some(row_group) = split.row_group {
+                builder = builder.with_row_groups(vec![row_group as usize]);
+            }
+            let options = FormatOptions::default();
+            let mut content = String::new();
+            for batch in builder.build()? {
+                let batch = batch?;
+                let formatters = batch
+                    .columns()
+                    .iter()
+                    .map(|c| ArrayFormatter::try_new(c.as_ref(), &options))
+                    .collect::<Result<Vec<_>, _>>()?;
+                for row in 0..batch.num_rows() {
+                    let fields: Vec<String> = formatters
+                        .iter()
+                        .map(|f| f.value(row).to_string())
+                        .collect();
+                    content.push_str(&fields.join("\t"));
+                    content.push('\n');
+                }
+            }
+            Ok(content)
+        }
+    }
+}
+
+/// The records of a split as returned by `read_split`, each with its line terminator:
+/// the lines of a text split, the rows of a parquet split. Together they make up the
+/// whole content, in order.
+pub fn split_records(content: &str, format: InputFormat) -> Vec<&str> {
+    match format {
+        // `read_split` writes each parquet row as one line
+        InputFormat::Text | InputFormat::Parquet => content.split_inclusive('\n').collect(),
+    }
+}
+
+/// Writes reduce output records to `path` in the given format.
+pub fn write_output(
+    path: &str,
+    format: OutputFormat,
+    records: &[(String, String)],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file = File::create(path)?;
+    let value_type = match format {
+        OutputFormat::Text => {
+            for (key, value) in records {
+                writeln!(file, "{} {}", key, value)?;
+            }
+            file.flush()?;
+            return Ok(());
+        }
+        OutputFormat::Parquet(value_type) => value_type,
+    };
+
+    let keys: ArrayRef = Arc::new(StringArray::from_iter_values(
+        records.iter().map(|(k, _)| k.as_str()),
+    ));
+    let values: ArrayRef = match value_type {
+        ValueType::Utf8 => Arc::new(StringArray::from_iter_values(
+            records.iter().map(|(_, v)| v.as_str()),
+        )),
+        ValueType::Int64 => Arc::new(Int64Array::from(
+            records
+                .iter()
+                .map(|(_, v)| v.trim().parse::<i64>())
+                .collect::<Result<Vec<_>, _>>()?,
+        )),
+        ValueType::Float64 => Arc::new(Float64Array::from(
+            records
+                .iter()
+                .map(|(_, v)| v.trim().parse::<f64>())
+                .collect::<Result<Vec<_>, _>>()?,
+        )),
+    };
+
+    let schema = Arc::new(format.schema().expect("parquet output has a schema"));
+    let batch = RecordBatch::try_new(schema.clone(), vec![keys, values])?;
+    let mut writer = ArrowWriter::try_new(file, schema, None)?;
+    writer.write(&batch)?;
+    writer.close()?;
+    Ok(())
+}
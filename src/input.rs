@@ -0,0 +1,171 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+/// Expands input specifications into the list of files a job reads.
+///
+/// Each spec is one of:
+/// - `@manifest.txt`: a file listing one spec per line (blank lines and `#` comments ignored),
+///   with relative entries resolved against the manifest's directory
+/// - a glob pattern such as `input/*.txt`
+/// - a directory, walked recursively (hidden entries are skipped)
+/// - a plain file
+///
+/// Globs and directory walks leave out files that are not input: hidden files, job
+/// bookkeeping such as `_SUCCESS` or `_counters`, and the uncommitted `.tmp` output of
+/// task attempts.
+///
+/// Every resulting file is opened once so missing or unreadable inputs are reported
+/// before any task is scheduled.
+pub fn expand_inputs(specs: &[String]) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut files = Vec::new();
+    for spec in specs {
+        expand_spec(spec, Path::new(""), &mut Vec::new(), &mut files)?;
+    }
+
+    let mut seen = HashSet::new();
+    files.retain(|f| seen.insert(f.clone()));
+
+    if files.is_empty() {
+        return Err(format!("no input files found in {:?}", specs).into());
+    }
+    for file in &files {
+        File::open(file).map_err(|e| format!("input '{}' is not readable: {}", file, e))?;
+    }
+    Ok(files)
+}
+
+/// Expands one spec into `files`. `manifests` holds the canonical paths of the manifests
+/// being expanded, outermost first, so a manifest that includes itself is reported.
+fn expand_spec(
+    spec: &str,
+    base: &Path,
+    manifests: &mut Vec<PathBuf>,
+    files: &mut Vec<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(manifest) = spec.strip_prefix('@') {
+        let manifest = base.join(manifest);
+        let content = fs::read_to_string(&manifest)
+            .map_err(|e| format!("cannot read manifest '{}': {}", manifest.display(), e))?;
+        let canonical = fs::canonicalize(&manifest)?;
+        if manifests.contains(&canonical) {
+            return Err(format!("manifest '{}' includes itself", manifest.display()).into());
+        }
+        manifests.push(canonical);
+        let dir = manifest.parent().unwrap_or(Path::new("")).to_path_buf();
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            expand_spec(line, &dir, manifests, files)?;
+        }
+        manifests.pop();
+        return Ok(());
+    }
+
+    let path = base.join(spec);
+    let path_str = path.to_string_lossy().to_string();
+
+    if spec.contains(['*', '?', '[']) {
+        let mut matched = Vec::new();
+        for entry in glob::glob(&path_str)? {
+            let entry = entry?;
+            if entry.is_file() && is_input_file(&entry) {
+                matched.push(entry.to_string_lossy().to_string());
+            }
+        }
+        if matched.is_empty() {
+            return Err(format!("glob '{}' matched no files", path_str).into());
+        }
+        files.extend(matched);
+    } else if path.is_dir() {
+        walk_dir(&path, files)?;
+    } else if path.exists() {
+        files.push(path_str);
+    } else {
+        return Err(format!("input '{}' does not exist", path_str).into());
+    }
+    Ok(())
+}
+
+fn walk_dir(dir: &Path, files: &mut Vec<String>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)
+        .map_err(|e| format!("cannot read directory '{}': {}", dir.display(), e))?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<_, _>>()?;
+    entries.sort();
+
+    for entry in entries {
+        let hidden = entry
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+        if hidden {
+            continue;
+        }
+        if entry.is_dir() {
+            walk_dir(&entry, files)?;
+        } else if is_input_file(&entry) {
+            files.push(entry.to_string_lossy().to_string());
+        }
+    }
+    Ok(())
+}
+
+/// Whether a file found by a glob or directory walk is input rather than a hidden file,
+/// job bookkeeping (`_SUCCESS`, `_job.toml`, ...) or an attempt's uncommitted `.tmp` file.
+fn is_input_file(path: &Path) -> bool {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    !name.starts_with(['.', '_']) && !name.ends_with(".tmp")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mr-input-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn expansion_skips_job_files_and_attempt_output() {
+        let dir = temp_dir("skip");
+        for name in [
+            "mr-out-0",
+            "mr-out-1",
+            "mr-out-2.3.tmp",
+            "_SUCCESS",
+            "_job.toml",
+            "_counters",
+            "_skipped",
+            ".hidden",
+        ] {
+            fs::write(dir.join(name), "a 1\n").unwrap();
+        }
+        let expected = vec![
+            dir.join("mr-out-0").to_string_lossy().to_string(),
+            dir.join("mr-out-1").to_string_lossy().to_string(),
+        ];
+
+        let walked = expand_inputs(&[dir.to_string_lossy().to_string()]).unwrap();
+        assert_eq!(walked, expected);
+        let globbed = expand_inputs(&[format!("{}/*", dir.display())]).unwrap();
+        assert_eq!(globbed, expected);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn manifest_cycle_is_an_error() {
+        let dir = temp_dir("cycle");
+        fs::write(dir.join("a.txt"), "@b.txt\n").unwrap();
+        fs::write(dir.join("b.txt"), "@a.txt\n").unwrap();
+        let err = expand_inputs(&[format!("@{}", dir.join("a.txt").display())]).unwrap_err();
+        assert!(err.to_string().contains("includes itself"), "{}", err);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,125 @@
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::Mutex;
+
+use crate::apps;
+use crate::format::{InputFormat, OutputFormat};
+use crate::job::JobSpec;
+use crate::master::Master;
+use crate::models::Iteration;
+use crate::registry::JobRegistry;
+use crate::rpc::Phase;
+
+/// Output directory of iteration `n` of an iterative job.
+pub fn iteration_dir(spec: &JobSpec, n: u32) -> String {
+    format!("{}/iter-{}", spec.output_dir, n)
+}
+
+/// The plain job run as iteration `n`: the first reads the job's inputs, later ones the
+/// previous iteration's committed output (the glob never matches `.tmp` attempt files).
+pub fn iteration_spec(spec: &JobSpec, n: u32) -> JobSpec {
+    let mut job = spec.clone();
+    job.iterate = None;
+    job.name = Some(format!("{}#{}", spec.display_name(), n));
+    job.output_dir = iteration_dir(spec, n);
+    if n > 0 {
+        job.inputs = vec![format!("{}/mr-out-*", iteration_dir(spec, n - 1))];
+        job.input_format = match spec.output_format {
+            OutputFormat::Parquet(_) => InputFormat::Parquet,
+            OutputFormat::Text => InputFormat::Text,
+        };
+    }
+    job
+}
+
+/// Runs an iterative job on the served `registry` until its convergence function holds
+/// or `max_iterations` is reached, then closes the registry. Each iteration's output stays
+/// in its own directory; `_iterations` in the job's output directory records their
+/// counters, and `_SUCCESS` is written once the job converges. Returns the number of
+/// iterations run.
+pub async fn run_iterations(
+    spec: JobSpec,
+    registry: Arc<Mutex<JobRegistry>>,
+) -> Result<u32, Box<dyn std::error::Error + Send + Sync>> {
+    let iterate = spec
+        .iterate
+        .clone()
+        .ok_or("job has no [iterate] settings")?;
+    let converged = apps::convergence(&iterate.until)
+        .ok_or_else(|| format!("unknown convergence function '{}'", iterate.until))?;
+
+    fs::create_dir_all(&spec.output_dir)?;
+    let _ = fs::remove_file(format!("{}/_SUCCESS", spec.output_dir));
+    let mut log_file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(format!("{}/_iterations", spec.output_dir))?;
+
+    let mut previous: Option<BTreeMap<String, i64>> = None;
+    for n in 0..iterate.max_iterations {
+        let job_spec = iteration_spec(&spec, n);
+        let job = tokio::task::spawn_blocking(move || {
+            Master::from_spec(job_spec).map_err(|e| e.to_string())
+        })
+        .await??;
+        let job_id = registry.lock().await.add(job);
+        log::info!("Started iteration {} as job {}", n, job_id);
+
+        let counters = loop {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            let mut registry = registry.lock().await;
+            let Some(job) = registry
+                .jobs
+                .get(&job_id)
+                .filter(|job| job.phase != Phase::Cancelled)
+            else {
+                registry.open = false;
+                return Err(format!("iteration {} (job {}) was cancelled", n, job_id).into());
+            };
+            if job.phase == Phase::Failed {
+                let reason = job.failure.clone().unwrap_or_default();
+                registry.open = false;
+                return Err(format!("iteration {} (job {}) failed: {}", n, job_id, reason).into());
+            }
+            if job.phase == Phase::Done {
+                break job.counters.clone();
+            }
+        };
+
+        let output_dir = iteration_dir(&spec, n);
+        let previous_output_dir = n.checked_sub(1).map(|p| iteration_dir(&spec, p));
+        let done = converged(&Iteration {
+            number: n,
+            spec: &iterate,
+            counters: &counters,
+            previous: previous.as_ref(),
+            output_dir: &output_dir,
+            previous_output_dir: previous_output_dir.as_deref(),
+        });
+        let summary: Vec<String> = counters
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect();
+        writeln!(log_file, "{} {} {}", n, output_dir, summary.join(" "))?;
+
+        if done {
+            log::info!("Converged after {} iterations", n + 1);
+            fs::write(format!("{}/_SUCCESS", spec.output_dir), "")?;
+            registry.lock().await.open = false;
+            return Ok(n + 1);
+        }
+        previous = Some(counters);
+    }
+
+    log::warn!(
+        "Did not converge within {} iterations",
+        iterate.max_iterations
+    );
+    registry.lock().await.open = false;
+    Ok(iterate.max_iterations)
+}
@@ -0,0 +1,436 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::apps::{self, Reducer};
+use crate::format::{InputFormat, OutputFormat};
+use crate::models::Partitioner;
+
+/// Declarative description of a job, usually loaded from a TOML file.
+///
+/// ```toml
+/// app = "wc"
+/// inputs = ["input/"]
+/// n_reduce = 5
+/// output_format = "parquet:int64"
+///
+/// [config]
+/// pattern = "error"
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct JobSpec {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub depends_on: Vec<String>, // names of pipeline stages that must finish first
+    pub app: String,
+    #[serde(default)]
+    pub inputs: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub datasets: Vec<Dataset>, // tagged inputs for apps with a tagged reducer
+    #[serde(default)]
+    pub input_format: InputFormat,
+    #[serde(default = "default_n_reduce")]
+    pub n_reduce: u32, // 0 runs a map-only job
+    #[serde(default)]
+    pub partitioner: Partitioner,
+    #[serde(default)]
+    pub output_format: OutputFormat,
+    #[serde(default = "default_output_dir")]
+    pub output_dir: String,
+    #[serde(default = "default_task_timeout_secs")]
+    pub task_timeout_secs: u64,
+    #[serde(default = "default_health_check_interval_secs")]
+    pub health_check_interval_secs: u64,
+    #[serde(default = "default_backup_threshold_secs")]
+    pub backup_threshold_secs: u64,
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32, // failed or timed-out attempts per task before the job fails
+    #[serde(default, skip_serializing_if = "SpeculationSpec::is_default")]
+    pub speculation: SpeculationSpec,
+    #[serde(default, skip_serializing_if = "BlacklistSpec::is_default")]
+    pub blacklist: BlacklistSpec,
+    #[serde(default)]
+    pub config: BTreeMap<String, String>, // user key/values for the app
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cache_files: Vec<String>, // side files copied to every worker
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iterate: Option<IterateSpec>, // re-run the job on its own output until it converges
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub skip: Option<SkipSpec>, // skip records that make the map function panic
+}
+
+/// One named input of a multi-dataset (join) job.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Dataset {
+    pub tag: String,
+    pub inputs: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mapper: Option<String>, // defaults to the app's map function
+}
+
+/// When backup copies of slow tasks are launched. Attempts become eligible after
+/// `backup_threshold_secs`.
+///
+/// ```toml
+/// [speculation]
+/// reduce = false
+/// remaining_fraction = 0.2
+/// max_backups = 2
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SpeculationSpec {
+    #[serde(default = "default_true")]
+    pub map: bool,
+    #[serde(default = "default_true")]
+    pub reduce: bool,
+    #[serde(default = "default_remaining_fraction")]
+    pub remaining_fraction: f64, // only speculate once at most this share of a phase is unfinished
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_backups: Option<u32>, // backups running at once; defaults to a tenth of the tasks
+}
+
+/// When a worker stops getting this job's tasks. A worker on which `max_failures`
+/// distinct tasks failed within `window_secs` is blacklisted by name for `cooldown_secs`,
+/// unless that would leave the job no registered worker; `max_failures = 0` disables
+/// blacklisting.
+///
+/// ```toml
+/// [blacklist]
+/// max_failures = 2
+/// cooldown_secs = 60
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BlacklistSpec {
+    #[serde(default = "default_blacklist_max_failures")]
+    pub max_failures: u32,
+    #[serde(default = "default_blacklist_window_secs")]
+    pub window_secs: u64,
+    #[serde(default = "default_blacklist_cooldown_secs")]
+    pub cooldown_secs: u64,
+}
+
+/// Bad-record skipping. Once a map task has failed `after_failures` times, its next
+/// attempts run the map function over ranges of records (text lines, parquet rows),
+/// narrowing down and skipping those that make it panic. The map function then sees
+/// slices of its split rather than the whole split, so it must not depend on where a
+/// chunk starts or ends. Skipped records are counted in `skipped_records` and, with
+/// `side_file`, listed in `_skipped` in the output directory. A map task that skips more
+/// than `max_skipped` records fails the job.
+///
+/// ```toml
+/// [skip]
+/// after_failures = 2
+/// max_skipped = 100
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SkipSpec {
+    #[serde(default = "default_skip_after_failures")]
+    pub after_failures: u32,
+    #[serde(default = "default_max_skipped")]
+    pub max_skipped: u64, // per map task
+    #[serde(default = "default_true")]
+    pub side_file: bool,
+}
+
+/// Settings of an iterative job. Iteration `n` writes to `<output_dir>/iter-<n>` and
+/// reads the output of iteration `n - 1`; the first iteration reads the job's inputs.
+///
+/// ```toml
+/// [iterate]
+/// max_iterations = 20
+/// until = "counter_below"
+/// counter = "rank_delta_micros"
+/// threshold = 1000
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct IterateSpec {
+    #[serde(default = "default_max_iterations")]
+    pub max_iterations: u32,
+    pub until: String, // convergence function, see `apps::convergence`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub counter: Option<String>, // counter the convergence function looks at
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub threshold: Option<i64>,
+}
+
+fn default_n_reduce() -> u32 {
+    5
+}
+
+fn default_output_dir() -> String {
+    "output".to_string()
+}
+
+fn default_task_timeout_secs() -> u64 {
+    600 // dead workers are caught sooner by missed heartbeats; this catches hung attempts
+}
+
+fn default_health_check_interval_secs() -> u64 {
+    10
+}
+
+fn default_backup_threshold_secs() -> u64 {
+    10
+}
+
+fn default_max_attempts() -> u32 {
+    4
+}
+
+fn default_blacklist_max_failures() -> u32 {
+    3
+}
+
+fn default_blacklist_window_secs() -> u64 {
+    600
+}
+
+fn default_blacklist_cooldown_secs() -> u64 {
+    300
+}
+
+fn default_skip_after_failures() -> u32 {
+    2
+}
+
+fn default_max_skipped() -> u64 {
+    100
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_remaining_fraction() -> f64 {
+    1.0
+}
+
+fn default_max_iterations() -> u32 {
+    10
+}
+
+impl Default for SpeculationSpec {
+    fn default() -> Self {
+        SpeculationSpec {
+            map: true,
+            reduce: true,
+            remaining_fraction: default_remaining_fraction(),
+            max_backups: None,
+        }
+    }
+}
+
+impl SpeculationSpec {
+    fn is_default(&self) -> bool {
+        *self == SpeculationSpec::default()
+    }
+}
+
+impl Default for BlacklistSpec {
+    fn default() -> Self {
+        BlacklistSpec {
+            max_failures: default_blacklist_max_failures(),
+            window_secs: default_blacklist_window_secs(),
+            cooldown_secs: default_blacklist_cooldown_secs(),
+        }
+    }
+}
+
+impl BlacklistSpec {
+    fn is_default(&self) -> bool {
+        *self == BlacklistSpec::default()
+    }
+}
+
+impl JobSpec {
+    /// A spec with default settings for `app` over `inputs`.
+    pub fn new(app: &str, inputs: Vec<String>) -> JobSpec {
+        JobSpec {
+            name: None,
+            depends_on: Vec::new(),
+            app: app.to_string(),
+            inputs,
+            datasets: Vec::new(),
+            input_format: InputFormat::default(),
+            n_reduce: default_n_reduce(),
+            partitioner: Partitioner::default(),
+            output_format: OutputFormat::default(),
+            output_dir: default_output_dir(),
+            task_timeout_secs: default_task_timeout_secs(),
+            health_check_interval_secs: default_health_check_interval_secs(),
+            backup_threshold_secs: default_backup_threshold_secs(),
+            max_attempts: default_max_attempts(),
+            speculation: SpeculationSpec::default(),
+            blacklist: BlacklistSpec::default(),
+            config: BTreeMap::new(),
+            cache_files: Vec::new(),
+            iterate: None,
+            skip: None,
+        }
+    }
+
+    pub fn from_file(path: &Path) -> Result<JobSpec, Box<dyn std::error::Error>> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("cannot read job spec '{}': {}", path.display(), e))?;
+        let spec = JobSpec::from_toml(&content)
+            .map_err(|e| format!("invalid job spec '{}': {}", path.display(), e))?;
+        Ok(spec)
+    }
+
+    pub fn from_toml(content: &str) -> Result<JobSpec, Box<dyn std::error::Error>> {
+        let spec: JobSpec = toml::from_str(content)?;
+        spec.validate()?;
+        Ok(spec)
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).expect("job spec serializes to TOML")
+    }
+
+    /// Display name: the explicit `name`, or the app name.
+    pub fn display_name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.app)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let Some(app) = apps::lookup(&self.app) else {
+            return Err(format!(
+                "unknown app '{}' (available: {})",
+                self.app,
+                apps::names().join(", ")
+            ));
+        };
+        for key in app.required_config {
+            if !self.config.contains_key(*key) {
+                return Err(format!("app '{}' requires config key '{}'", self.app, key));
+            }
+        }
+        match app.reduce {
+            Reducer::Values(_) if !self.datasets.is_empty() => {
+                return Err(format!("app '{}' does not accept datasets", self.app));
+            }
+            Reducer::Values(_) if self.inputs.is_empty() => {
+                return Err("at least one input is required".to_string());
+            }
+            Reducer::Tagged(_) if !self.inputs.is_empty() || self.datasets.len() < 2 => {
+                return Err(format!(
+                    "app '{}' needs at least two datasets instead of inputs",
+                    self.app
+                ));
+            }
+            _ => {}
+        }
+        let mut tags = HashSet::new();
+        for dataset in &self.datasets {
+            if dataset.tag.is_empty() || !tags.insert(dataset.tag.as_str()) {
+                return Err(format!(
+                    "dataset tag '{}' is empty or repeated",
+                    dataset.tag
+                ));
+            }
+            if dataset.inputs.is_empty() {
+                return Err(format!("dataset '{}' has no inputs", dataset.tag));
+            }
+            if let Some(mapper) = &dataset.mapper
+                && apps::mapper(mapper).is_none()
+            {
+                return Err(format!(
+                    "dataset '{}': unknown mapper '{}'",
+                    dataset.tag, mapper
+                ));
+            }
+        }
+        if self.n_reduce == 0 && matches!(app.reduce, Reducer::Tagged(_)) {
+            return Err(format!(
+                "app '{}' cannot run map-only (n_reduce = 0)",
+                self.app
+            ));
+        }
+        if self.health_check_interval_secs == 0 {
+            return Err("health_check_interval_secs must be positive".into());
+        }
+        if self.task_timeout_secs > 0 && self.health_check_interval_secs > self.task_timeout_secs {
+            return Err(format!(
+                "health_check_interval_secs ({}) must not exceed task_timeout_secs ({})",
+                self.health_check_interval_secs, self.task_timeout_secs
+            ));
+        }
+        let mut cache_names = HashSet::new();
+        for path in &self.cache_files {
+            let name = Path::new(path).file_name().unwrap_or_default();
+            if !cache_names.insert(name) {
+                return Err(format!("duplicate cache file name {:?}", name));
+            }
+        }
+        if self.max_attempts == 0 {
+            return Err("max_attempts must be at least 1".to_string());
+        }
+        let speculation = &self.speculation;
+        if !(speculation.remaining_fraction > 0.0 && speculation.remaining_fraction <= 1.0) {
+            return Err(format!(
+                "speculation.remaining_fraction ({}) must be in (0, 1]",
+                speculation.remaining_fraction
+            ));
+        }
+        if speculation.max_backups == Some(0) {
+            return Err(
+                "speculation.max_backups must be at least 1; set map and reduce to false to disable"
+                    .to_string(),
+            );
+        }
+        let blacklist = &self.blacklist;
+        if blacklist.max_failures > 0
+            && (blacklist.window_secs == 0 || blacklist.cooldown_secs == 0)
+        {
+            return Err(
+                "blacklist.window_secs and blacklist.cooldown_secs must be greater than 0"
+                    .to_string(),
+            );
+        }
+        if let Some(skip) = &self.skip
+            && (skip.after_failures == 0 || skip.after_failures >= self.max_attempts)
+        {
+            return Err(format!(
+                "skip.after_failures ({}) must be at least 1 and below max_attempts ({})",
+                skip.after_failures, self.max_attempts
+            ));
+        }
+        if self.skip.as_ref().is_some_and(|skip| skip.max_skipped == 0) {
+            return Err("skip.max_skipped must be at least 1".to_string());
+        }
+        if let Some(iterate) = &self.iterate {
+            if iterate.max_iterations == 0 {
+                return Err("iterate.max_iterations must be at least 1".to_string());
+            }
+            if apps::convergence(&iterate.until).is_none() {
+                return Err(format!(
+                    "unknown convergence function '{}' (available: {})",
+                    iterate.until,
+                    apps::convergence_names().join(", ")
+                ));
+            }
+            if iterate.until.starts_with("counter_") && iterate.counter.is_none() {
+                return Err(format!(
+                    "iterate.until = '{}' needs iterate.counter",
+                    iterate.until
+                ));
+            }
+            if iterate.until == "counter_below" && iterate.threshold.is_none() {
+                return Err("iterate.until = 'counter_below' needs iterate.threshold".to_string());
+            }
+            if !self.datasets.is_empty() {
+                return Err("iterative jobs read their own output and cannot use datasets".into());
+            }
+        }
+        Ok(())
+    }
+}
@@ -4,3 +4,11 @@ pub mod rpc;
 pub mod worker;
 pub mod server;
 pub mod client;
+pub mod format;
+pub mod input;
+pub mod apps;
+pub mod job;
+pub mod cache;
+pub mod pipeline;
+pub mod registry;
+pub mod iterate;
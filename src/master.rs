@@ -1,10 +1,18 @@
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time::Duration;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime};
 
-use crate::rpc::{Phase, Request, Response, TaskStatus};
-use log;
+use crate::format::{self, InputFormat, InputSplit, OutputFormat};
+use crate::input;
+use crate::job::{BlacklistSpec, Dataset, JobSpec, SkipSpec};
+use crate::models::Partitioner;
+use crate::registry::WorkerInfo;
+use crate::cache;
+use crate::rpc::{
+    Attempt, AttemptOutcome, AttemptRef, CacheFile, Phase, Request, Response, TaskData,
+    TaskProgress, TaskStatus, TaskType,
+};
 
 pub struct Master {
     pub map_task: HashMap<u32, TaskStatus>,
@@ -12,57 +20,363 @@ pub struct Master {
     pub phase: crate::rpc::Phase,
     pub n_reduce: u32,
     pub input_files: Vec<String>,
+    pub splits: Vec<InputSplit>,
+    pub input_format: InputFormat,
+    pub output_format: OutputFormat,
     pub output: String,
     pub map_outputs: HashMap<u32, HashMap<u32, String>>,
+    pub app: String,
+    pub partitioner: Partitioner,
+    pub spec: Option<JobSpec>, // the spec this job was submitted with, if any
+    pub config: BTreeMap<String, String>,
+    pub map_attempts: HashMap<u32, u32>, // attempts launched per task
+    pub reduce_attempts: HashMap<u32, u32>,
+    pub counters: BTreeMap<String, i64>, // summed over the winning attempt of each task
+    pub cache_files: Vec<CacheFile>,
+    pub cache_sources: HashMap<String, String>, // content hash -> path served to workers
+    pub mappers: BTreeMap<String, Option<String>>, // dataset tag -> mapper override
+    pub job_id: u32, // assigned by the job registry
+    pub speculation: SpeculationPolicy,
+    pub map_history: HashMap<u32, Vec<Attempt>>, // attempts per task, oldest first
+    pub reduce_history: HashMap<u32, Vec<Attempt>>,
+    pub max_attempts: u32, // failed or timed-out attempts per task before the job fails
+    pub failure: Option<String>, // why the job failed
+    pub skip: Option<SkipSpec>,  // bad-record skipping, if enabled
+    pub skipped: BTreeMap<u32, Vec<(u64, u64)>>, // map task -> record ranges its winner skipped
+    pub blacklist: BlacklistSpec,
+    // both keyed by worker name, so a worker that registers again stays blacklisted
+    pub worker_failures: HashMap<String, Vec<(Instant, TaskType, u32)>>, // failed tasks per worker
+    pub blacklisted: BTreeMap<String, Instant>, // worker -> when its blacklisting ends
 }
 
-impl Master {
-    fn should_schedule_backup(&self) -> bool {
-        let total = self.map_task.len();
-        let completed = self.map_task.values().filter(|s| matches!(s,TaskStatus::Completed)).count();
+/// When a job launches backup copies of running tasks, and when it gives up on them.
+#[derive(Debug, Clone)]
+pub struct SpeculationPolicy {
+    pub map: bool, // back up slow map tasks
+    pub reduce: bool,
+    pub remaining_fraction: f64, // speculate once at most this share of a phase is unfinished
+    pub min_runtime: Duration,   // how long an attempt runs before it may get a backup
+    pub max_backups: Option<u32>, // backups running at once; defaults to a tenth of the tasks
+    pub timeout: Option<Duration>, // running attempts older than this are rescheduled
+}
+
+impl SpeculationPolicy {
+    pub fn from_spec(spec: &JobSpec) -> SpeculationPolicy {
+        SpeculationPolicy {
+            map: spec.speculation.map,
+            reduce: spec.speculation.reduce,
+            remaining_fraction: spec.speculation.remaining_fraction,
+            min_runtime: Duration::from_secs(spec.backup_threshold_secs),
+            max_backups: spec.speculation.max_backups,
+            timeout: Some(Duration::from_secs(spec.task_timeout_secs)).filter(|t| !t.is_zero()),
+        }
+    }
+}
 
-        let remaining = total - completed;
-        remaining <= (total as f64 * 0.05) as usize
+impl Default for SpeculationPolicy {
+    fn default() -> Self {
+        SpeculationPolicy {
+            map: true,
+            reduce: true,
+            remaining_fraction: 1.0,
+            min_runtime: Duration::from_secs(10),
+            max_backups: None,
+            timeout: None,
+        }
     }
+}
+
+impl Master {
     pub fn new(input_files: Vec<String>, n_reduce: u32, output_path: String) -> Master {
         let mut map_task = HashMap::new();
         for (i, _) in input_files.iter().enumerate() {
             map_task.insert(i as u32, TaskStatus::Idle);
         }
+        let splits = input_files
+            .iter()
+            .map(|path| InputSplit {
+                path: path.clone(),
+                row_group: None,
+                tag: None,
+            })
+            .collect();
         Master {
             map_task,
             reduce_task: HashMap::new(),
             phase: Phase::Map,
             n_reduce,
             input_files,
+            splits,
+            input_format: InputFormat::Text,
+            output_format: OutputFormat::Text,
             map_outputs: HashMap::new(),
             output: output_path,
+            app: "wc".to_string(),
+            partitioner: Partitioner::Hash,
+            spec: None,
+            config: BTreeMap::new(),
+            map_attempts: HashMap::new(),
+            reduce_attempts: HashMap::new(),
+            counters: BTreeMap::new(),
+            cache_files: Vec::new(),
+            cache_sources: HashMap::new(),
+            mappers: BTreeMap::new(),
+            job_id: 0,
+            speculation: SpeculationPolicy::default(),
+            map_history: HashMap::new(),
+            reduce_history: HashMap::new(),
+            max_attempts: 4,
+            failure: None,
+            skip: None,
+            skipped: BTreeMap::new(),
+            blacklist: BlacklistSpec::default(),
+            worker_failures: HashMap::new(),
+            blacklisted: BTreeMap::new(),
+        }
+    }
+
+    /// Validates a job spec, expands its inputs and builds the job's master state.
+    /// The spec is saved as `_job.toml` in the output directory.
+    pub fn from_spec(spec: JobSpec) -> Result<Master, Box<dyn std::error::Error>> {
+        spec.validate()?;
+        let mut master = if spec.datasets.is_empty() {
+            let input_files = input::expand_inputs(&spec.inputs)?;
+            log::info!(
+                "Job '{}': expanded {:?} into {} input files",
+                spec.display_name(),
+                spec.inputs,
+                input_files.len()
+            );
+            Master::new(input_files, spec.n_reduce, spec.output_dir.clone())
+                .with_formats(spec.input_format, spec.output_format)?
+        } else {
+            Master::new(Vec::new(), spec.n_reduce, spec.output_dir.clone())
+                .with_formats(spec.input_format, spec.output_format)?
+                .with_datasets(&spec.datasets)?
+        };
+        master.speculation = SpeculationPolicy::from_spec(&spec);
+        master.max_attempts = spec.max_attempts;
+        master.skip = spec.skip.clone();
+        master.blacklist = spec.blacklist.clone();
+        master.app = spec.app.clone();
+        master.partitioner = spec.partitioner;
+        master.config = spec.config.clone();
+        for path in &spec.cache_files {
+            let content =
+                fs::read(path).map_err(|e| format!("cannot read cache file '{}': {}", path, e))?;
+            let hash = cache::content_hash(&content);
+            let name = Path::new(path)
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_else(|| path.clone());
+            master.cache_files.push(CacheFile {
+                name,
+                hash: hash.clone(),
+            });
+            master.cache_sources.insert(hash, path.clone());
+        }
+
+        fs::create_dir_all(&spec.output_dir)?;
+        let _ = fs::remove_file(format!("{}/_SUCCESS", spec.output_dir));
+        fs::write(format!("{}/_job.toml", spec.output_dir), spec.to_toml())?;
+        master.spec = Some(spec);
+        Ok(master)
+    }
+
+    /// Estimated time until the job finishes: the remaining tasks times the mean duration
+    /// of succeeded attempts, spread over the workers that have run the job's tasks.
+    /// None until a task of the current phase has succeeded, and for a job that failed or
+    /// was cancelled.
+    pub fn eta(&self) -> Option<Duration> {
+        match self.phase {
+            Phase::Done => return Some(Duration::ZERO),
+            Phase::Failed | Phase::Cancelled => return None,
+            _ => {}
+        }
+        let completed = |tasks: &HashMap<u32, TaskStatus>| {
+            tasks
+                .values()
+                .filter(|s| matches!(s, TaskStatus::Completed))
+                .count() as u32
+        };
+        let maps_left = self.map_task.len() as u32 - completed(&self.map_task);
+        let reduces_left = self.n_reduce - completed(&self.reduce_task);
+        let map_mean = mean_duration(&self.map_history);
+        let reduce_mean = mean_duration(&self.reduce_history).or(map_mean);
+
+        let mut work = Duration::ZERO;
+        if maps_left > 0 {
+            work += map_mean? * maps_left;
+        }
+        if reduces_left > 0 {
+            work += reduce_mean? * reduces_left;
+        }
+        let workers: HashSet<&str> = self
+            .map_history
+            .values()
+            .chain(self.reduce_history.values())
+            .flatten()
+            .map(|a| a.worker.as_str())
+            .collect();
+        Some(work / workers.len().max(1) as u32)
+    }
+
+    /// Display name: the spec's name, or the app name.
+    pub fn name(&self) -> String {
+        self.spec
+            .as_ref()
+            .map(|s| s.display_name().to_string())
+            .unwrap_or_else(|| self.app.clone())
+    }
+
+    /// Sets the job's input and output formats, re-planning map splits for the input format.
+    pub fn with_formats(
+        mut self,
+        input_format: InputFormat,
+        output_format: OutputFormat,
+    ) -> Result<Master, Box<dyn std::error::Error>> {
+        self.splits = format::plan_splits(&self.input_files, input_format)?;
+        self.map_task = (0..self.splits.len() as u32)
+            .map(|i| (i, TaskStatus::Idle))
+            .collect();
+        self.input_format = input_format;
+        self.output_format = output_format;
+        Ok(self)
+    }
+
+    /// Replaces the job's inputs with tagged datasets, planning splits for each.
+    pub fn with_datasets(mut self, datasets: &[Dataset]) -> Result<Master, Box<dyn std::error::Error>> {
+        self.input_files.clear();
+        self.splits.clear();
+        for dataset in datasets {
+            let files = input::expand_inputs(&dataset.inputs)?;
+            log::info!(
+                "Dataset '{}': expanded {:?} into {} input files",
+                dataset.tag,
+                dataset.inputs,
+                files.len()
+            );
+            for mut split in format::plan_splits(&files, self.input_format)? {
+                split.tag = Some(dataset.tag.clone());
+                self.splits.push(split);
+            }
+            self.input_files.extend(files);
+            self.mappers.insert(dataset.tag.clone(), dataset.mapper.clone());
+        }
+        self.map_task = (0..self.splits.len() as u32)
+            .map(|i| (i, TaskStatus::Idle))
+            .collect();
+        Ok(self)
+    }
+
+    fn map_task_data(&mut self, id: u32, worker: &str, backup: bool) -> TaskData {
+        let skip_bad_records = self.skip.as_ref().is_some_and(|skip| {
+            failed_attempts(&self.map_history, id) >= skip.after_failures as usize
+        });
+        let attempt_id = next_attempt(&mut self.map_attempts, id);
+        start_attempt(&mut self.map_history, id, attempt_id, worker, backup);
+        let split = &self.splits[id as usize];
+        TaskData {
+            job_id: self.job_id,
+            task_id: id,
+            input_files: vec![split.path.clone()],
+            n_reduce: self.n_reduce,
+            output_path: self.output.clone(),
+            row_group: split.row_group,
+            input_format: self.input_format,
+            output_format: self.output_format,
+            app: self.app.clone(),
+            partitioner: self.partitioner,
+            attempt_id,
+            config: self.config.clone(),
+            cache_files: self.cache_files.clone(),
+            tag: split.tag.clone(),
+            mapper: split
+                .tag
+                .as_ref()
+                .and_then(|tag| self.mappers.get(tag).cloned().flatten()),
+            datasets: self.mappers.keys().cloned().collect(),
+            skip_bad_records,
+        }
+    }
+
+    fn reduce_task_data(&mut self, id: u32, worker: &str, backup: bool) -> TaskData {
+        let attempt_id = next_attempt(&mut self.reduce_attempts, id);
+        start_attempt(&mut self.reduce_history, id, attempt_id, worker, backup);
+        let mut input_files = Vec::new();
+        for files in self.map_outputs.values() {
+            if let Some(file) = files.get(&id) {
+                input_files.push(file.clone());
+            }
+        }
+        TaskData {
+            job_id: self.job_id,
+            task_id: id,
+            input_files,
+            n_reduce: self.n_reduce,
+            output_path: self.output.clone(),
+            row_group: None,
+            input_format: self.input_format,
+            output_format: self.output_format,
+            app: self.app.clone(),
+            partitioner: self.partitioner,
+            attempt_id,
+            config: self.config.clone(),
+            cache_files: self.cache_files.clone(),
+            tag: None,
+            mapper: None,
+            datasets: self.mappers.keys().cloned().collect(),
+            skip_bad_records: false,
         }
     }
 
     pub fn handle_request(&mut self, req: Request) -> Response {
         match req {
-            Request::GetTask => self.get_task(),
-            Request::MapDone { task_id, files } => {
-                self.handle_map_done(task_id, files);
+            Request::GetTask { worker } => self.get_task(&worker),
+            Request::MapDone { .. } | Request::ReduceDone { .. }
+                if matches!(self.phase, Phase::Cancelled | Phase::Failed) =>
+            {
+                Response::Abort
+            }
+            Request::MapDone {
+                task_id,
+                attempt_id,
+                files,
+                counters,
+                skipped,
+            } => {
+                self.handle_map_done(task_id, attempt_id, files, counters, skipped);
+                Response::NoTask
+            }
+            Request::ReduceDone {
+                task_id,
+                attempt_id,
+                counters,
+            } => {
+                self.handle_reduce_done(task_id, attempt_id, counters);
                 Response::NoTask
             }
-            Request::ReduceDone { task_id } => {
-                self.handle_reduce_done(task_id);
+            Request::Failed { .. } if self.phase.is_finished() => Response::NoTask,
+            Request::Failed {
+                task_type,
+                task_id,
+                attempt_id,
+                error,
+            } => {
+                self.handle_failure(task_type, task_id, attempt_id, error);
                 Response::NoTask
             }
         }
     }
 
-    fn get_task(&mut self) -> Response {
+    fn get_task(&mut self, worker: &str) -> Response {
+        if self.is_blacklisted(WorkerInfo::name_of(worker)) {
+            return Response::NoTask;
+        }
 
         match self.phase {
             Phase::Map => {
-                let task_id = self
-                    .map_task
-                    .iter()
-                    .find(|(_, status)| matches!(status, TaskStatus::Idle))
-                    .map(|(id, _)| *id);
+                let task_id = idle_task(&self.map_task, &self.map_history, worker);
 
                 if let Some(id) = task_id {
                     self.map_task.insert(
@@ -75,58 +389,22 @@ impl Master {
 
                     return Response::Task {
                         task_type: crate::rpc::TaskType::Map,
-                        task_data: crate::rpc::TaskData {
-                            task_id: id,
-                            input_files: vec![self.input_files[id as usize].clone()],
-                            n_reduce: self.n_reduce,
-                            output_path: self.output.clone(),
-                        },
+                        task_data: Box::new(self.map_task_data(id, worker, false)),
                     };
                 }
-            if self.should_schedule_backup() {
-                
-                let backup_threshold = Duration::from_secs(10);
-                let backup_task = self
-                    .map_task
-                    .iter()
-                    .find(|(_, status)| {
-                        matches!(status, TaskStatus::InProgress { start_time, backup_scheduled } 
-                            if !backup_scheduled && start_time.elapsed() > backup_threshold)
-                    })
-                    .map(|(id, _)| *id);
-
-                if let Some(id) = backup_task {
+                if let Some(id) = self.pick_straggler(TaskType::Map, worker) {
                     log::info!("Scheduling backup for map task {}", id);
-                    if let Some(TaskStatus::InProgress { start_time, .. }) = self.map_task.get(&id)
-                    {
-                        self.map_task.insert(
-                            id,
-                            TaskStatus::InProgress {
-                                start_time: *start_time,
-                                backup_scheduled: true,
-                            },
-                        );
-                    }
+                    schedule_backup(&mut self.map_task, id);
                     return Response::Task {
-                        task_type: crate::rpc::TaskType::Map,
-                        task_data: crate::rpc::TaskData {
-                            task_id: id,
-                            input_files: vec![self.input_files[id as usize].clone()],
-                            n_reduce: self.n_reduce,
-                            output_path: self.output.clone(),
-                        },
+                        task_type: TaskType::Map,
+                        task_data: Box::new(self.map_task_data(id, worker, true)),
                     };
                 }
-            }
 
                 Response::NoTask
             }
             Phase::Reduce => {
-                let task_id = self
-                    .reduce_task
-                    .iter()
-                    .find(|(_, status)| matches!(status, TaskStatus::Idle))
-                    .map(|(id, _)| *id);
+                let task_id = idle_task(&self.reduce_task, &self.reduce_history, worker);
                 if let Some(id) = task_id {
                     self.reduce_task.insert(
                         id,
@@ -135,77 +413,181 @@ impl Master {
                             backup_scheduled: false,
                         },
                     );
-                    let mut input_files = Vec::new();
-                    for (_map_id, files) in &self.map_outputs {
-                        if let Some(file) = files.get(&id) {
-                            input_files.push(file.clone());
-                        }
-                    }
                     return Response::Task {
                         task_type: crate::rpc::TaskType::Reduce,
-                        task_data: crate::rpc::TaskData {
-                            task_id: id,
-                            input_files,
-                            n_reduce: self.n_reduce,
-                            output_path: self.output.clone(),
-                        },
+                        task_data: Box::new(self.reduce_task_data(id, worker, false)),
                     };
                 }
 
-                let backup_threshold = Duration::from_secs(10);
-                let backup_task = self
-                    .reduce_task
-                    .iter()
-                    .find(|(_, status)| {
-                        matches!(status, TaskStatus::InProgress { start_time, backup_scheduled }
-                            if !backup_scheduled && start_time.elapsed() > backup_threshold)
-                    })
-                    .map(|(id, _)| *id);
-
-                if let Some(id) = backup_task {
+                if let Some(id) = self.pick_straggler(TaskType::Reduce, worker) {
                     log::info!("Scheduling backup for reduce task {}", id);
-                    if let Some(TaskStatus::InProgress { start_time, .. }) =
-                        self.reduce_task.get(&id)
-                    {
-                        self.reduce_task.insert(
-                            id,
-                            TaskStatus::InProgress {
-                                start_time: *start_time,
-                                backup_scheduled: true,
-                            },
-                        );
-                    }
-                    let mut input_files = Vec::new();
-                    for (_map_id, files) in &self.map_outputs {
-                        if let Some(file) = files.get(&id) {
-                            input_files.push(file.clone());
-                        }
-                    }
+                    schedule_backup(&mut self.reduce_task, id);
                     return Response::Task {
-                        task_type: crate::rpc::TaskType::Reduce,
-                        task_data: crate::rpc::TaskData {
-                            task_id: id,
-                            input_files,
-                            n_reduce: self.n_reduce,
-                            output_path: self.output.clone(),
-                        },
+                        task_type: TaskType::Reduce,
+                        task_data: Box::new(self.reduce_task_data(id, worker, true)),
                     };
                 }
 
                 Response::NoTask
             }
-            Phase::Done => Response::Exit,
+            Phase::Done | Phase::Cancelled | Phase::Failed => Response::Exit,
         }
     }
 
-    fn handle_map_done(&mut self, task_id: u32, files: HashMap<u32, String>) {
-        // Check if task is still InProgress (might have been reset by health check)
-        if let Some(status) = self.map_task.get(&task_id) {
+    fn add_counters(&mut self, counters: BTreeMap<String, i64>) {
+        for (name, delta) in counters {
+            *self.counters.entry(name).or_insert(0) += delta;
+        }
+    }
+
+    /// Cancels an unfinished job: no more of its tasks are handed out, reports from its
+    /// running tasks are answered with `Abort`, and its intermediate and temporary files
+    /// are removed. Returns false if the job had already finished.
+    pub fn cancel(&mut self) -> bool {
+        if self.phase.is_finished() {
+            return false;
+        }
+        self.abandon(Phase::Cancelled);
+        log::info!("Job {} cancelled", self.job_id);
+        true
+    }
+
+    /// Fails the job for good, cleaning up like a cancelled job.
+    fn fail(&mut self, reason: String) {
+        log::error!("Job {} failed: {}", self.job_id, reason);
+        self.failure = Some(reason);
+        self.abandon(Phase::Failed);
+    }
+
+    /// Stops an unfinished job: kills its running attempts and removes its intermediate
+    /// and temporary files, the partitions already committed and any summary files, so
+    /// no partial output is left behind.
+    fn abandon(&mut self, phase: Phase) {
+        self.phase = phase;
+        // nothing runs for an abandoned job any more
+        for (task_id, status) in &mut self.map_task {
+            if matches!(status, TaskStatus::InProgress { .. }) {
+                *status = TaskStatus::Idle;
+            }
+            kill_attempts(&mut self.map_history, *task_id);
+        }
+        for (task_id, status) in &mut self.reduce_task {
             if matches!(status, TaskStatus::InProgress { .. }) {
-                self.map_task.insert(task_id, TaskStatus::Completed);
-                self.map_outputs.insert(task_id, files);
+                *status = TaskStatus::Idle;
             }
-            // If status is Idle, it was already reset by health check - ignore
+            kill_attempts(&mut self.reduce_history, *task_id);
+        }
+        for files in self.map_outputs.values() {
+            for file in files.values() {
+                let _ = fs::remove_file(file);
+            }
+        }
+        if let Ok(entries) = fs::read_dir(&self.output) {
+            for entry in entries.flatten() {
+                if entry.file_name().to_string_lossy().ends_with(".tmp") {
+                    let _ = fs::remove_file(entry.path());
+                }
+            }
+        }
+        // a map-only job commits its map tasks' output directly
+        let partitions = match self.n_reduce {
+            0 => self.map_task.len() as u32,
+            n => n,
+        };
+        for partition in 0..partitions {
+            let _ = fs::remove_file(self.output_format.output_file(&self.output, partition));
+        }
+        for name in ["_counters", "_skipped"] {
+            let _ = fs::remove_file(format!("{}/{}", self.output, name));
+        }
+    }
+
+    /// Marks the job done and commits it: final counters, then the `_SUCCESS` marker.
+    fn finish(&mut self) {
+        self.phase = Phase::Done;
+        self.write_counters();
+        self.write_skipped();
+        let path = format!("{}/_SUCCESS", self.output);
+        if let Err(e) = fs::write(&path, "") {
+            log::error!("Failed to write {}: {}", path, e);
+        }
+    }
+
+    /// Writes the final counters as `name value` lines to `_counters` in the output directory.
+    fn write_counters(&self) {
+        let content: String = self
+            .counters
+            .iter()
+            .map(|(name, value)| format!("{} {}\n", name, value))
+            .collect();
+        let path = format!("{}/_counters", self.output);
+        if let Err(e) = fs::write(&path, content) {
+            log::error!("Failed to write counters to {}: {}", path, e);
+        }
+    }
+
+    /// Lists the records skipped in skip mode in `_skipped` in the output directory, one
+    /// `split<TAB>start<TAB>end` line per range. Records are numbered from 0 within their
+    /// split and `end` is exclusive.
+    fn write_skipped(&self) {
+        if self.skipped.is_empty() || !self.skip.as_ref().is_some_and(|skip| skip.side_file) {
+            return;
+        }
+        let mut content = String::new();
+        for (task_id, ranges) in &self.skipped {
+            let split = &self.splits[*task_id as usize];
+            let name = match split.row_group {
+                Some(row_group) => format!("{}#{}", split.path, row_group),
+                None => split.path.clone(),
+            };
+            for (start, end) in ranges {
+                content.push_str(&format!("{}\t{}\t{}\n", name, start, end));
+            }
+        }
+        let path = format!("{}/_skipped", self.output);
+        if let Err(e) = fs::write(&path, content) {
+            log::error!("Failed to write {}: {}", path, e);
+        }
+    }
+
+    fn handle_map_done(
+        &mut self,
+        task_id: u32,
+        attempt_id: u32,
+        files: HashMap<u32, String>,
+        counters: BTreeMap<String, i64>,
+        skipped: Vec<(u64, u64)>,
+    ) {
+        // Check if task is still InProgress (might have been reset by health check)
+        // If status is Idle, it was already reset by health check - ignore
+        let n_skipped: u64 = skipped.iter().map(|(start, end)| end - start).sum();
+        let max_skipped = self.skip.as_ref().map_or(0, |skip| skip.max_skipped);
+        if let Some(status) = self.map_task.get(&task_id)
+            && matches!(status, TaskStatus::InProgress { .. })
+            && n_skipped > max_skipped
+        {
+            // skipping is deterministic, so another attempt would skip as many
+            end_attempt(&mut self.map_history, task_id, attempt_id, AttemptOutcome::Failed);
+            self.fail(format!(
+                "map task {} skipped {} records, more than skip.max_skipped ({})",
+                task_id, n_skipped, max_skipped
+            ));
+            return;
+        }
+        if let Some(status) = self.map_task.get(&task_id)
+            && matches!(status, TaskStatus::InProgress { .. })
+        {
+            self.map_task.insert(task_id, TaskStatus::Completed);
+            self.map_outputs.insert(task_id, files);
+            self.add_counters(counters);
+            if !skipped.is_empty() {
+                log::warn!("Map task {} skipped {} bad record ranges", task_id, skipped.len());
+                self.skipped.insert(task_id, skipped);
+            }
+            end_attempt(&mut self.map_history, task_id, attempt_id, AttemptOutcome::Succeeded);
+            kill_attempts(&mut self.map_history, task_id);
+        } else {
+            end_attempt(&mut self.map_history, task_id, attempt_id, AttemptOutcome::Superseded);
         }
 
         // Check if ALL map tasks are completed
@@ -213,7 +595,10 @@ impl Master {
             .map_task
             .values()
             .all(|s| matches!(s, TaskStatus::Completed));
-        if all_done && self.phase == Phase::Map {
+        if all_done && self.phase == Phase::Map && self.n_reduce == 0 {
+            self.finish();
+            log::info!("All map tasks complete, map-only job finished!");
+        } else if all_done && self.phase == Phase::Map {
             self.phase = Phase::Reduce;
             for i in 0..self.n_reduce {
                 self.reduce_task.insert(i, TaskStatus::Idle);
@@ -222,12 +607,22 @@ impl Master {
         }
     }
 
-    fn handle_reduce_done(&mut self, task_id: u32) {
+    fn handle_reduce_done(
+        &mut self,
+        task_id: u32,
+        attempt_id: u32,
+        counters: BTreeMap<String, i64>,
+    ) {
         // Check if task is still InProgress
-        if let Some(status) = self.reduce_task.get(&task_id) {
-            if matches!(status, TaskStatus::InProgress { .. }) {
-                self.reduce_task.insert(task_id, TaskStatus::Completed);
-            }
+        if let Some(status) = self.reduce_task.get(&task_id)
+            && matches!(status, TaskStatus::InProgress { .. })
+        {
+            self.reduce_task.insert(task_id, TaskStatus::Completed);
+            self.add_counters(counters);
+            end_attempt(&mut self.reduce_history, task_id, attempt_id, AttemptOutcome::Succeeded);
+            kill_attempts(&mut self.reduce_history, task_id);
+        } else {
+            end_attempt(&mut self.reduce_history, task_id, attempt_id, AttemptOutcome::Superseded);
         }
 
         let all_done = self
@@ -235,52 +630,511 @@ impl Master {
             .values()
             .all(|s| matches!(s, TaskStatus::Completed));
         if all_done && self.phase == Phase::Reduce {
-            self.phase = Phase::Done;
+            self.finish();
             log::info!("All reduce tasks complete, job finished!");
         }
     }
 
+    /// Records a failed attempt and re-queues its task right away, unless another attempt
+    /// of it is still running. Fails the job once the task has failed `max_attempts` times.
+    fn handle_failure(
+        &mut self,
+        task_type: TaskType,
+        task_id: u32,
+        attempt_id: u32,
+        error: String,
+    ) {
+        log::warn!(
+            "{} task {} attempt {} failed: {}",
+            task_type,
+            task_id,
+            attempt_id,
+            error
+        );
+        let (tasks, history) = match task_type {
+            TaskType::Map => (&mut self.map_task, &mut self.map_history),
+            _ => (&mut self.reduce_task, &mut self.reduce_history),
+        };
+        let Some(attempts) = history.get_mut(&task_id) else {
+            return;
+        };
+        let mut worker = None;
+        if let Some(attempt) = attempts
+            .iter_mut()
+            .find(|a| a.attempt_id == attempt_id && a.outcome == AttemptOutcome::Running)
+        {
+            attempt.duration = attempt.started.elapsed().ok();
+            attempt.outcome = AttemptOutcome::Failed;
+            attempt.error = Some(error);
+            worker = Some(WorkerInfo::name_of(&attempt.worker).to_string());
+        }
+        if let Some(status) = tasks.get_mut(&task_id)
+            && matches!(status, TaskStatus::InProgress { .. })
+            && !attempts.iter().any(|a| a.outcome == AttemptOutcome::Running)
+        {
+            *status = TaskStatus::Idle;
+        }
+        if let Some(worker) = worker {
+            self.record_worker_failure(&worker, task_type, task_id);
+        }
+        self.check_attempts(task_type, task_id);
+    }
+
+    /// Counts a failed task against the worker it ran on, and blacklists the worker once
+    /// `max_failures` distinct tasks failed on it within the window.
+    fn record_worker_failure(&mut self, worker: &str, task_type: TaskType, task_id: u32) {
+        if self.blacklist.max_failures == 0 {
+            return;
+        }
+        let window = Duration::from_secs(self.blacklist.window_secs);
+        let failures = self.worker_failures.entry(worker.to_string()).or_default();
+        failures.retain(|(at, _, _)| at.elapsed() <= window);
+        failures.push((Instant::now(), task_type, task_id));
+        let mut tasks: Vec<(TaskType, u32)> = Vec::new();
+        for (_, task_type, task_id) in failures.iter() {
+            if !tasks.contains(&(*task_type, *task_id)) {
+                tasks.push((*task_type, *task_id));
+            }
+        }
+        if tasks.len() < self.blacklist.max_failures as usize {
+            return;
+        }
+        let cooldown = Duration::from_secs(self.blacklist.cooldown_secs);
+        log::warn!(
+            "Blacklisting worker {} for {:?}: {} tasks failed on it",
+            worker,
+            cooldown,
+            tasks.len()
+        );
+        self.worker_failures.remove(worker);
+        self.blacklisted
+            .insert(worker.to_string(), Instant::now() + cooldown);
+    }
+
+    /// Whether `worker` may not run this job's tasks. Blacklistings past their cool-down
+    /// are lifted.
+    fn is_blacklisted(&mut self, worker: &str) -> bool {
+        match self.blacklisted.get(worker) {
+            Some(until) if Instant::now() < *until => true,
+            Some(_) => {
+                log::info!("Worker {} is no longer blacklisted", worker);
+                self.blacklisted.remove(worker);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Lifts blacklistings that would leave the job without a worker among the registered
+    /// `workers`: while all of them are blacklisted, the one whose cool-down ends first
+    /// is let back.
+    pub fn limit_blacklist(&mut self, workers: &BTreeSet<&str>) {
+        let now = Instant::now();
+        self.blacklisted.retain(|_, until| *until > now);
+        while !workers.is_empty() && workers.iter().all(|w| self.blacklisted.contains_key(*w)) {
+            let Some(worker) = self
+                .blacklisted
+                .iter()
+                .min_by_key(|(_, until)| **until)
+                .map(|(worker, _)| worker.clone())
+            else {
+                break;
+            };
+            log::warn!(
+                "Lifting blacklisting of worker {}: job {} has no other worker",
+                worker,
+                self.job_id
+            );
+            self.blacklisted.remove(&worker);
+        }
+    }
+
+    /// Workers currently blacklisted, with the time left on their cool-down.
+    pub fn blacklisted_workers(&self) -> Vec<(String, Duration)> {
+        let now = Instant::now();
+        self.blacklisted
+            .iter()
+            .filter(|(_, until)| **until > now)
+            .map(|(worker, until)| (worker.clone(), *until - now))
+            .collect()
+    }
+
+    /// Fails the job if a task has used up its attempts.
+    fn check_attempts(&mut self, task_type: TaskType, task_id: u32) {
+        let history = match task_type {
+            TaskType::Map => &self.map_history,
+            _ => &self.reduce_history,
+        };
+        let failed: Vec<&Attempt> = history
+            .get(&task_id)
+            .into_iter()
+            .flatten()
+            .filter(|a| matches!(a.outcome, AttemptOutcome::Failed | AttemptOutcome::TimedOut))
+            .collect();
+        if failed.len() < self.max_attempts as usize {
+            return;
+        }
+        let last_error = match failed.last() {
+            Some(Attempt {
+                error: Some(error), ..
+            }) => error.clone(),
+            _ => "timed out".to_string(),
+        };
+        let reason = format!(
+            "{} task {} failed {} times; last error: {}",
+            task_type,
+            task_id,
+            failed.len(),
+            last_error
+        );
+        self.fail(reason);
+    }
+
     /// Health check - resets stuck tasks to Idle
-    pub fn health_check(&mut self, timeout_secs: u64) {
-        let timeout = Duration::from_secs(timeout_secs);
+    pub fn health_check(&mut self, timeout: Duration) {
+        let mut timed_out = Vec::new();
 
         // Check map tasks
         for (task_id, status) in &mut self.map_task {
-            if let TaskStatus::InProgress { start_time, .. } = status {
-                if start_time.elapsed() > timeout {
-                    log::warn!("Map task {} timed out, resetting to Idle", task_id);
-                    *status = TaskStatus::Idle;
-                }
+            if let TaskStatus::InProgress { start_time, .. } = status
+                && start_time.elapsed() > timeout
+            {
+                log::warn!("Map task {} timed out, resetting to Idle", task_id);
+                *status = TaskStatus::Idle;
+                time_out_attempts(&mut self.map_history, *task_id);
+                timed_out.push((TaskType::Map, *task_id));
             }
         }
 
         for (task_id, status) in &mut self.reduce_task {
-            if let TaskStatus::InProgress { start_time, .. } = status {
-                if start_time.elapsed() > timeout {
-                    log::warn!("Reduce task {} timed out, resetting to Idle", task_id);
-                    *status = TaskStatus::Idle;
-                }
+            if let TaskStatus::InProgress { start_time, .. } = status
+                && start_time.elapsed() > timeout
+            {
+                log::warn!("Reduce task {} timed out, resetting to Idle", task_id);
+                *status = TaskStatus::Idle;
+                time_out_attempts(&mut self.reduce_history, *task_id);
+                timed_out.push((TaskType::Reduce, *task_id));
+            }
+        }
+
+        for (task_type, task_id) in timed_out {
+            if !self.phase.is_finished() {
+                self.check_attempts(task_type, task_id);
             }
         }
     }
-}
 
-/// Start background health check thread
-pub fn start_health_check(master: Arc<Mutex<Master>>, timeout_secs: u64, check_interval_secs: u64) {
-    thread::spawn(move || {
-        loop {
-            thread::sleep(Duration::from_secs(check_interval_secs));
+    /// Backup attempts launched so far, and how many of them finished first.
+    pub fn backup_stats(&self) -> (u32, u32) {
+        let backups = self
+            .map_history
+            .values()
+            .chain(self.reduce_history.values())
+            .flatten()
+            .filter(|a| a.backup);
+        let (mut launched, mut won) = (0, 0);
+        for attempt in backups {
+            launched += 1;
+            if attempt.outcome == AttemptOutcome::Succeeded {
+                won += 1;
+            }
+        }
+        (launched, won)
+    }
 
-            let mut master = master.lock().unwrap();
+    /// Records the progress a worker reported for one of its running attempts.
+    pub fn record_progress(&mut self, progress: &TaskProgress) {
+        let attempt = &progress.attempt;
+        let history = match attempt.task_type {
+            TaskType::Map => &mut self.map_history,
+            _ => &mut self.reduce_history,
+        };
+        if let Some(running) = history.get_mut(&attempt.task_id).and_then(|attempts| {
+            attempts
+                .iter_mut()
+                .find(|a| a.attempt_id == attempt.attempt_id)
+        }) && running.outcome == AttemptOutcome::Running
+        {
+            running.progress = progress.fraction;
+        }
+    }
 
-            // Only check if not done
-            if matches!(master.phase, Phase::Done) {
-                log::info!("Health check: Job complete, stopping");
-                break;
+    /// Whether a worker should stop an attempt it is still running: its job was
+    /// cancelled, another attempt of the task has already won, or the attempt timed out.
+    pub fn should_abort(&self, attempt: &AttemptRef) -> bool {
+        let (tasks, history) = match attempt.task_type {
+            TaskType::Map => (&self.map_task, &self.map_history),
+            _ => (&self.reduce_task, &self.reduce_history),
+        };
+        let timed_out = history.get(&attempt.task_id).is_some_and(|attempts| {
+            attempts.iter().any(|a| {
+                a.attempt_id == attempt.attempt_id && a.outcome == AttemptOutcome::TimedOut
+            })
+        });
+        matches!(self.phase, Phase::Cancelled | Phase::Failed)
+            || matches!(tasks.get(&attempt.task_id), Some(TaskStatus::Completed))
+            || timed_out
+    }
+
+    /// Picks a running task to back up, LATE-style. Each running attempt's total runtime
+    /// is estimated from its reported progress; tasks expected to take more than
+    /// `SLOWDOWN` times as long as their peers are stragglers, and the one expected to
+    /// finish last is backed up first. The speculation policy decides whether the phase
+    /// speculates at all, how long attempts run before they are considered and how many
+    /// backups may run at once. A backup never goes to the worker running the original.
+    fn pick_straggler(&self, task_type: TaskType, worker: &str) -> Option<u32> {
+        let policy = &self.speculation;
+        let (tasks, history, enabled) = match task_type {
+            TaskType::Map => (&self.map_task, &self.map_history, policy.map),
+            _ => (&self.reduce_task, &self.reduce_history, policy.reduce),
+        };
+        if !enabled {
+            return None;
+        }
+        let unfinished = tasks
+            .values()
+            .filter(|s| !matches!(s, TaskStatus::Completed))
+            .count();
+        if unfinished as f64 > policy.remaining_fraction * tasks.len() as f64 {
+            return None;
+        }
+        let running_backups = history
+            .values()
+            .flatten()
+            .filter(|a| a.backup && a.outcome == AttemptOutcome::Running)
+            .count();
+        let max_backups = match policy.max_backups {
+            Some(max) => max as usize,
+            None => ((tasks.len() as f64 * DEFAULT_BACKUP_FRACTION).ceil() as usize).max(1),
+        };
+        if running_backups >= max_backups {
+            return None;
+        }
+
+        // (task, elapsed, estimated total) of each task's running attempt, in seconds;
+        // attempts stuck before their first progress report are left to the task timeout
+        let estimates: Vec<(u32, f64, f64)> = tasks
+            .iter()
+            .filter(|(_, status)| matches!(status, TaskStatus::InProgress { .. }))
+            .filter_map(|(id, _)| {
+                let attempt = history
+                    .get(id)?
+                    .iter()
+                    .find(|a| a.outcome == AttemptOutcome::Running)?;
+                let elapsed = attempt.started.elapsed().ok()?.as_secs_f64();
+                Some((*id, elapsed, estimated_total(elapsed, attempt.progress)?))
+            })
+            .collect();
+        // peers: finished attempts if there are any, otherwise the running ones
+        let peer_total = match mean_duration(history) {
+            Some(mean) => mean.as_secs_f64(),
+            None => {
+                let mut totals: Vec<f64> = estimates.iter().map(|e| e.2).collect();
+                totals.sort_by(f64::total_cmp);
+                *totals.get(totals.len() / 2)?
             }
+        };
+
+        let threshold = policy.min_runtime.as_secs_f64();
+        estimates
+            .into_iter()
+            .filter(|(id, elapsed, total)| {
+                matches!(
+                    tasks.get(id),
+                    Some(TaskStatus::InProgress {
+                        backup_scheduled: false,
+                        ..
+                    })
+                ) && *elapsed >= threshold
+                    && *total > SLOWDOWN * peer_total
+                    && !history[id]
+                        .iter()
+                        .any(|a| a.outcome == AttemptOutcome::Running && a.worker == worker)
+            })
+            .max_by(|a, b| (a.2 - a.1).total_cmp(&(b.2 - b.1)))
+            .map(|(id, _, _)| id)
+    }
 
-            log::debug!("Health check: Checking for stuck tasks...");
-            master.health_check(timeout_secs);
+    /// Marks the running attempts of an expired worker as lost. Tasks left without a
+    /// running attempt go back to Idle to be rescheduled.
+    pub fn release_worker(&mut self, worker: &str) {
+        for (task_id, status) in &mut self.map_task {
+            if lose_attempts(&mut self.map_history, *task_id, worker)
+                && matches!(status, TaskStatus::InProgress { .. })
+            {
+                log::warn!("Map task {} lost with worker {}, resetting to Idle", task_id, worker);
+                *status = TaskStatus::Idle;
+            }
+        }
+        for (task_id, status) in &mut self.reduce_task {
+            if lose_attempts(&mut self.reduce_history, *task_id, worker)
+                && matches!(status, TaskStatus::InProgress { .. })
+            {
+                log::warn!(
+                    "Reduce task {} lost with worker {}, resetting to Idle",
+                    task_id,
+                    worker
+                );
+                *status = TaskStatus::Idle;
+            }
         }
+    }
+
+    /// Number of this job's attempts running on `worker`.
+    pub fn running_attempts(&self, worker: &str) -> usize {
+        self.map_history
+            .values()
+            .chain(self.reduce_history.values())
+            .flatten()
+            .filter(|a| a.outcome == AttemptOutcome::Running && a.worker == worker)
+            .count()
+    }
+}
+
+/// A task is a straggler when its estimated runtime exceeds its peers' by this factor.
+const SLOWDOWN: f64 = 2.0;
+/// Share of a phase's tasks that may run a backup at once, unless the policy sets a limit.
+const DEFAULT_BACKUP_FRACTION: f64 = 0.1;
+
+/// Estimated total runtime of an attempt from its elapsed time and progress so far.
+/// None until the attempt has reported progress: a map attempt reports none while it
+/// reads its split, which says nothing about its speed.
+fn estimated_total(elapsed: f64, progress: f64) -> Option<f64> {
+    (progress > 0.0).then(|| elapsed / progress)
+}
+
+fn schedule_backup(tasks: &mut HashMap<u32, TaskStatus>, id: u32) {
+    if let Some(TaskStatus::InProgress {
+        backup_scheduled, ..
+    }) = tasks.get_mut(&id)
+    {
+        *backup_scheduled = true;
+    }
+}
+
+fn start_attempt(
+    history: &mut HashMap<u32, Vec<Attempt>>,
+    task_id: u32,
+    attempt_id: u32,
+    worker: &str,
+    backup: bool,
+) {
+    history.entry(task_id).or_default().push(Attempt {
+        attempt_id,
+        worker: worker.to_string(),
+        started: SystemTime::now(),
+        duration: None,
+        backup,
+        outcome: AttemptOutcome::Running,
+        progress: 0.0,
+        error: None,
     });
 }
+
+fn end_attempt(
+    history: &mut HashMap<u32, Vec<Attempt>>,
+    task_id: u32,
+    attempt_id: u32,
+    outcome: AttemptOutcome,
+) {
+    if let Some(attempt) = history
+        .get_mut(&task_id)
+        .and_then(|attempts| attempts.iter_mut().find(|a| a.attempt_id == attempt_id))
+        && attempt.outcome == AttemptOutcome::Running
+    {
+        attempt.duration = attempt.started.elapsed().ok();
+        attempt.outcome = outcome;
+    }
+}
+
+/// Marks a task's still-running attempts as killed, once another attempt has won or the
+/// job was cancelled. Their workers learn of it from their next heartbeat.
+fn kill_attempts(history: &mut HashMap<u32, Vec<Attempt>>, task_id: u32) {
+    for attempt in history.get_mut(&task_id).into_iter().flatten() {
+        if attempt.outcome == AttemptOutcome::Running {
+            attempt.duration = attempt.started.elapsed().ok();
+            attempt.outcome = AttemptOutcome::Killed;
+        }
+    }
+}
+
+/// An idle task to hand `worker`, preferring tasks that have not failed on it before, so
+/// a broken worker fails distinct tasks and gets blacklisted rather than using up the
+/// attempts of one task.
+fn idle_task(
+    tasks: &HashMap<u32, TaskStatus>,
+    history: &HashMap<u32, Vec<Attempt>>,
+    worker: &str,
+) -> Option<u32> {
+    let name = WorkerInfo::name_of(worker);
+    let failed_here = |id: &u32| {
+        history.get(id).is_some_and(|attempts| {
+            attempts.iter().any(|a| {
+                a.outcome == AttemptOutcome::Failed && WorkerInfo::name_of(&a.worker) == name
+            })
+        })
+    };
+    let idle = || {
+        tasks
+            .iter()
+            .filter(|(_, status)| matches!(status, TaskStatus::Idle))
+            .map(|(id, _)| *id)
+    };
+    idle().find(|id| !failed_here(id)).or_else(|| idle().next())
+}
+
+/// Failed or timed-out attempts of a task so far.
+fn failed_attempts(history: &HashMap<u32, Vec<Attempt>>, task_id: u32) -> usize {
+    history
+        .get(&task_id)
+        .into_iter()
+        .flatten()
+        .filter(|a| matches!(a.outcome, AttemptOutcome::Failed | AttemptOutcome::TimedOut))
+        .count()
+}
+
+fn time_out_attempts(history: &mut HashMap<u32, Vec<Attempt>>, task_id: u32) {
+    for attempt in history.get_mut(&task_id).into_iter().flatten() {
+        if attempt.outcome == AttemptOutcome::Running {
+            attempt.duration = attempt.started.elapsed().ok();
+            attempt.outcome = AttemptOutcome::TimedOut;
+        }
+    }
+}
+
+/// Marks `worker`'s running attempts of a task as lost. Returns true if the task has no
+/// running attempt left afterwards but had one of `worker`'s.
+fn lose_attempts(history: &mut HashMap<u32, Vec<Attempt>>, task_id: u32, worker: &str) -> bool {
+    let Some(attempts) = history.get_mut(&task_id) else {
+        return false;
+    };
+    let mut lost = false;
+    for attempt in attempts.iter_mut() {
+        if attempt.outcome == AttemptOutcome::Running && attempt.worker == worker {
+            attempt.duration = attempt.started.elapsed().ok();
+            attempt.outcome = AttemptOutcome::Lost;
+            lost = true;
+        }
+    }
+    lost && !attempts.iter().any(|a| a.outcome == AttemptOutcome::Running)
+}
+
+/// Mean duration of the succeeded attempts in `history`.
+fn mean_duration(history: &HashMap<u32, Vec<Attempt>>) -> Option<Duration> {
+    let durations: Vec<Duration> = history
+        .values()
+        .flatten()
+        .filter(|a| a.outcome == AttemptOutcome::Succeeded)
+        .filter_map(|a| a.duration)
+        .collect();
+    if durations.is_empty() {
+        return None;
+    }
+    Some(durations.iter().sum::<Duration>() / durations.len() as u32)
+}
+
+fn next_attempt(attempts: &mut HashMap<u32, u32>, task_id: u32) -> u32 {
+    let count = attempts.entry(task_id).or_insert(0);
+    *count += 1;
+    *count - 1
+}
@@ -1,23 +1,175 @@
-// use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use serde::{Deserialize, Serialize};
+
+use crate::job::IterateSpec;
 
-use std::collections::HashMap;
 #[derive(Clone)]
 pub struct KeyValue {
     pub key: String,
     pub value: String,
 }
 
-pub type MapFunction = fn(String, String) -> Vec<KeyValue>;
+/// Per-task information passed to map, combine and reduce functions.
+pub struct JobContext {
+    pub config: BTreeMap<String, String>, // user key/values from the job spec
+    pub task_id: u32,
+    pub attempt_id: u32,
+    pub partition: Option<u32>, // reduce partition, None for map tasks
+    pub counters: RefCell<BTreeMap<String, i64>>,
+    pub cache_files: BTreeMap<String, PathBuf>, // side file name -> local copy
+    pub datasets: Vec<String>,                  // tags of the job's input datasets, if any
+    pub tag: Option<String>,                    // dataset of the current map split
+}
+
+impl JobContext {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.config.get(key).map(String::as_str)
+    }
+
+    /// Local path of a side file from the job's distributed cache.
+    pub fn cache_path(&self, name: &str) -> Option<&Path> {
+        self.cache_files.get(name).map(PathBuf::as_path)
+    }
+
+    /// Adds `delta` to a named counter; the master sums counters across tasks.
+    pub fn incr(&self, counter: &str, delta: i64) {
+        *self
+            .counters
+            .borrow_mut()
+            .entry(counter.to_string())
+            .or_insert(0) += delta;
+    }
+}
+
+pub type MapFunction = fn(&JobContext, String, String) -> Vec<KeyValue>;
+
+pub type ReduceFunction = fn(&JobContext, String, Vec<String>) -> String;
+
+/// Reduce function for multi-dataset jobs: receives a key's values grouped by
+/// dataset tag and returns zero or more output values for the key.
+pub type JoinFunction = fn(&JobContext, String, BTreeMap<String, Vec<String>>) -> Vec<String>;
+
+/// What a convergence function sees after each iteration of an iterative job.
+pub struct Iteration<'a> {
+    pub number: u32, // 0 for the first iteration
+    pub spec: &'a IterateSpec,
+    pub counters: &'a BTreeMap<String, i64>,
+    pub previous: Option<&'a BTreeMap<String, i64>>, // counters of the iteration before
+    pub output_dir: &'a str,
+    pub previous_output_dir: Option<&'a str>,
+}
+
+/// Decides whether an iterative job has converged and can stop.
+pub type ConvergeFunction = fn(&Iteration) -> bool;
 
-pub type ReduceFunction = fn(String, Vec<String>) -> String;
+/// Decides which reduce partition a key belongs to.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(try_from = "String", into = "String")]
+pub enum Partitioner {
+    #[default]
+    Hash,
+    Range, // by first byte, so partitions hold contiguous, ordered key ranges
+}
+
+impl Partitioner {
+    pub fn partition(&self, key: &str, n_reduce: u32) -> u32 {
+        match self {
+            Partitioner::Hash => ihash(key) % n_reduce,
+            Partitioner::Range => {
+                let first = key.as_bytes().first().copied().unwrap_or(0) as u32;
+                first * n_reduce / 256
+            }
+        }
+    }
+}
+
+pub fn ihash(key: &str) -> u32 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish() as u32
+}
+
+impl fmt::Display for Partitioner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Partitioner::Hash => write!(f, "hash"),
+            Partitioner::Range => write!(f, "range"),
+        }
+    }
+}
+
+impl FromStr for Partitioner {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hash" => Ok(Partitioner::Hash),
+            "range" => Ok(Partitioner::Range),
+            _ => Err(format!("unknown partitioner '{}'", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for Partitioner {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Partitioner> for String {
+    fn from(p: Partitioner) -> String {
+        p.to_string()
+    }
+}
+
+/// Fraction of a running task completed, shared between the task and the worker's
+/// heartbeats, which may also ask the task to abort.
+#[derive(Default)]
+pub struct Progress {
+    done: AtomicU32, // millionths
+    abort: AtomicBool,
+}
+
+impl Progress {
+    pub fn set(&self, fraction: f64) {
+        let millionths = (fraction.clamp(0.0, 1.0) * 1e6) as u32;
+        self.done.store(millionths, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> f64 {
+        self.done.load(Ordering::Relaxed) as f64 / 1e6
+    }
+
+    /// Asks the task to stop at its next checkpoint.
+    pub fn abort(&self) {
+        self.abort.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        self.abort.load(Ordering::Relaxed)
+    }
+}
 
 pub enum Report {
     MapDone {
         taskid: u32,
         files: HashMap<u32, String>,
+        counters: BTreeMap<String, i64>,
+        skipped: Vec<(u64, u64)>, // [start, end) record ranges skipped in skip mode
     },
     ReducerDone {
         taskid: u32,
+        counters: BTreeMap<String, i64>,
     },
+    Aborted, // stopped at the master's request; nothing to report
     Exit,
 }
@@ -0,0 +1,488 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::format::{InputFormat, OutputFormat};
+use crate::job::JobSpec;
+use crate::master::Master;
+use crate::registry::JobRegistry;
+use crate::rpc::Phase;
+
+/// A workflow of jobs. Stages form a DAG: a stage runs once every stage it depends on
+/// has finished, and independent stages run concurrently on the same workers.
+///
+/// A stage depends on the stages named in `depends_on` and on any stage referenced by a
+/// `job:<name>` input, which stands for that stage's committed output. A stage without
+/// inputs reads the output of all its dependencies; a stage with neither inputs nor
+/// dependencies follows the stage before it, so a plain list of stages is a linear chain.
+///
+/// ```toml
+/// name = "report"
+///
+/// [[stages]]
+/// name = "count"
+/// app = "wc"
+/// inputs = ["input/"]
+/// output_dir = "out/count"
+///
+/// [[stages]]
+/// name = "errors"
+/// app = "grep"
+/// inputs = ["logs/"]
+/// output_dir = "out/errors"
+/// config = { pattern = "error" }
+///
+/// [[stages]]
+/// name = "summary"
+/// app = "wc"
+/// depends_on = ["count", "errors"]
+/// output_dir = "out/summary"
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PipelineSpec {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub stages: Vec<JobSpec>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StageState {
+    Pending,
+    Running,
+    Done,
+    Skipped, // already completed by an earlier run
+    Failed,
+    Cancelled, // its job was cancelled, or a stage it depends on did not complete
+}
+
+#[derive(Debug, Clone)]
+pub struct StageStatus {
+    pub name: String,
+    pub output_dir: String,
+    pub state: StageState,
+    pub depends_on: Vec<String>,
+    pub job_id: Option<u32>, // registry id once started
+}
+
+impl fmt::Display for StageState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StageState::Pending => write!(f, "pending"),
+            StageState::Running => write!(f, "running"),
+            StageState::Done => write!(f, "done"),
+            StageState::Skipped => write!(f, "skipped"),
+            StageState::Failed => write!(f, "failed"),
+            StageState::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+impl PipelineSpec {
+    pub fn from_file(path: &Path) -> Result<PipelineSpec, Box<dyn std::error::Error>> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("cannot read pipeline '{}': {}", path.display(), e))?;
+        let mut pipeline: PipelineSpec = toml::from_str(&content)
+            .map_err(|e| format!("invalid pipeline '{}': {}", path.display(), e))?;
+        pipeline
+            .resolve()
+            .and_then(|_| pipeline.validate())
+            .map_err(|e| format!("invalid pipeline '{}': {}", path.display(), e))?;
+        Ok(pipeline)
+    }
+
+    /// Stage names: the explicit `name`, or the stage's index.
+    pub fn ids(&self) -> Vec<String> {
+        self.stages
+            .iter()
+            .enumerate()
+            .map(|(i, stage)| stage.name.clone().unwrap_or_else(|| i.to_string()))
+            .collect()
+    }
+
+    /// Expands `job:<name>` inputs and implicit chaining into explicit inputs and
+    /// `depends_on` lists.
+    fn resolve(&mut self) -> Result<(), String> {
+        let ids = self.ids();
+        let outputs: BTreeMap<String, (String, OutputFormat)> = ids
+            .iter()
+            .zip(&self.stages)
+            .map(|(id, s)| (id.clone(), (s.output_dir.clone(), s.output_format)))
+            .collect();
+        let output_glob = |id: &str| -> Result<String, String> {
+            outputs
+                .get(id)
+                .map(|(dir, _)| format!("{}/mr-out-*", dir))
+                .ok_or_else(|| format!("unknown stage '{}'", id))
+        };
+
+        for (i, stage) in self.stages.iter_mut().enumerate() {
+            let mut deps = stage.depends_on.clone();
+            let mut read = Vec::new(); // stages whose output this stage reads
+            let inputs = stage
+                .inputs
+                .iter_mut()
+                .chain(stage.datasets.iter_mut().flat_map(|d| d.inputs.iter_mut()));
+            for input in inputs {
+                if let Some(id) = input.strip_prefix("job:") {
+                    let id = id.to_string();
+                    *input = output_glob(&id)?;
+                    read.push(id.clone());
+                    deps.push(id);
+                }
+            }
+            if deps.is_empty() && stage.inputs.is_empty() && stage.datasets.is_empty() && i > 0 {
+                deps.push(ids[i - 1].clone());
+            }
+            if stage.inputs.is_empty() && stage.datasets.is_empty() {
+                for id in &deps {
+                    stage.inputs.push(output_glob(id)?);
+                    read.push(id.clone());
+                }
+            }
+            let parquet =
+                |id: &String| matches!(outputs.get(id), Some((_, OutputFormat::Parquet(_))));
+            if read.iter().any(parquet) {
+                if !read.iter().all(parquet) {
+                    return Err(format!(
+                        "stage '{}' reads both parquet and text stage outputs",
+                        ids[i]
+                    ));
+                }
+                stage.input_format = InputFormat::Parquet;
+            }
+            let mut seen = HashSet::new();
+            deps.retain(|id| seen.insert(id.clone()));
+            stage.depends_on = deps;
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.stages.is_empty() {
+            return Err("a pipeline needs at least one stage".to_string());
+        }
+        let ids = self.ids();
+        let mut names = HashSet::new();
+        let mut dirs = HashSet::new();
+        for (id, stage) in ids.iter().zip(&self.stages) {
+            stage
+                .validate()
+                .map_err(|e| format!("stage '{}': {}", id, e))?;
+            if stage.iterate.is_some() {
+                return Err(format!(
+                    "stage '{}': iterative jobs cannot be pipeline stages",
+                    id
+                ));
+            }
+            if !names.insert(id.as_str()) {
+                return Err(format!("duplicate stage name '{}'", id));
+            }
+            if !dirs.insert(stage.output_dir.as_str()) {
+                return Err(format!(
+                    "stage '{}' reuses output_dir '{}'",
+                    id, stage.output_dir
+                ));
+            }
+        }
+        for (id, stage) in ids.iter().zip(&self.stages) {
+            for dep in &stage.depends_on {
+                if !names.contains(dep.as_str()) {
+                    return Err(format!("stage '{}' depends on unknown stage '{}'", id, dep));
+                }
+            }
+        }
+
+        // every stage must become runnable once the stages before it in dependency order have run
+        let mut finished: HashSet<&str> = HashSet::new();
+        while finished.len() < ids.len() {
+            let ready: Vec<&str> = ids
+                .iter()
+                .zip(&self.stages)
+                .filter(|(id, stage)| {
+                    !finished.contains(id.as_str())
+                        && stage
+                            .depends_on
+                            .iter()
+                            .all(|d| finished.contains(d.as_str()))
+                })
+                .map(|(id, _)| id.as_str())
+                .collect();
+            if ready.is_empty() {
+                return Err("stage dependencies form a cycle".to_string());
+            }
+            finished.extend(ready);
+        }
+        Ok(())
+    }
+
+    pub fn display_name(&self) -> &str {
+        self.name.as_deref().unwrap_or("pipeline")
+    }
+
+    pub fn statuses(&self) -> Vec<StageStatus> {
+        self.ids()
+            .into_iter()
+            .zip(&self.stages)
+            .map(|(id, stage)| StageStatus {
+                name: id,
+                output_dir: stage.output_dir.clone(),
+                state: StageState::Pending,
+                depends_on: stage.depends_on.clone(),
+                job_id: None,
+            })
+            .collect()
+    }
+}
+
+/// Whether `spec` already ran to completion: its output directory holds a `_SUCCESS`
+/// marker and the `_job.toml` of that run matches the spec.
+pub fn is_complete(spec: &JobSpec) -> bool {
+    let dir = Path::new(&spec.output_dir);
+    dir.join("_SUCCESS").exists()
+        && fs::read_to_string(dir.join("_job.toml")).is_ok_and(|saved| saved == spec.to_toml())
+}
+
+/// Runs a pipeline to completion on the served `registry`, submitting each stage as soon
+/// as its dependencies are done. A stage that fails cancels every stage downstream of it;
+/// independent stages keep running. With `resume`, stages completed by an earlier run are
+/// skipped, unless a stage they depend on ran again. Closes the registry when no stage is
+/// left to run.
+pub async fn run_pipeline(
+    pipeline: PipelineSpec,
+    registry: Arc<Mutex<JobRegistry>>,
+    stages: Arc<Mutex<Vec<StageStatus>>>,
+    resume: bool,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    loop {
+        let ready = {
+            // lock order: stages, then registry
+            let mut stages = stages.lock().await;
+            let mut registry = registry.lock().await;
+
+            for stage in stages.iter_mut() {
+                if stage.state != StageState::Running {
+                    continue;
+                }
+                match stage.job_id.and_then(|id| registry.jobs.get(&id)) {
+                    Some(job) if job.phase == Phase::Done => {
+                        stage.state = StageState::Done;
+                        log::info!("Stage '{}' complete", stage.name);
+                    }
+                    Some(job) if !job.phase.is_finished() => {}
+                    Some(job) if job.phase == Phase::Failed => {
+                        stage.state = StageState::Failed;
+                        log::error!(
+                            "Stage '{}' failed: {}",
+                            stage.name,
+                            job.failure.as_deref().unwrap_or("unknown error")
+                        );
+                    }
+                    _ => {
+                        stage.state = StageState::Cancelled;
+                        log::warn!("Stage '{}' was cancelled", stage.name);
+                    }
+                }
+            }
+
+            let mut ready = Vec::new();
+            for i in 0..stages.len() {
+                if stages[i].state != StageState::Pending {
+                    continue;
+                }
+                let dep_states: Vec<StageState> = stages[i]
+                    .depends_on
+                    .iter()
+                    .filter_map(|dep| stages.iter().find(|s| s.name == *dep))
+                    .map(|s| s.state)
+                    .collect();
+                if dep_states
+                    .iter()
+                    .any(|s| matches!(s, StageState::Failed | StageState::Cancelled))
+                {
+                    log::warn!(
+                        "Stage '{}' cancelled: a dependency did not complete",
+                        stages[i].name
+                    );
+                    stages[i].state = StageState::Cancelled;
+                    continue;
+                }
+                if !dep_states
+                    .iter()
+                    .all(|s| matches!(s, StageState::Done | StageState::Skipped))
+                {
+                    continue;
+                }
+
+                // output built from an upstream stage that ran again is stale
+                let upstream_unchanged = dep_states.iter().all(|s| *s == StageState::Skipped);
+                if resume && upstream_unchanged && is_complete(&pipeline.stages[i]) {
+                    log::info!("Stage '{}' already complete, skipping", stages[i].name);
+                    stages[i].state = StageState::Skipped;
+                    continue;
+                }
+                ready.push(i);
+            }
+
+            let active = stages
+                .iter()
+                .any(|s| matches!(s.state, StageState::Pending | StageState::Running));
+            if !active {
+                registry.open = false;
+                let failed: Vec<&str> = stages
+                    .iter()
+                    .filter(|s| matches!(s.state, StageState::Failed | StageState::Cancelled))
+                    .map(|s| s.name.as_str())
+                    .collect();
+                if !failed.is_empty() {
+                    return Err(
+                        format!("pipeline stages did not complete: {}", failed.join(", ")).into(),
+                    );
+                }
+                return Ok(());
+            }
+            ready
+        };
+
+        for i in ready {
+            // expanding inputs and hashing cache files blocks, so it runs without the locks
+            let spec = pipeline.stages[i].clone();
+            let job = tokio::task::spawn_blocking(move || {
+                Master::from_spec(spec).map_err(|e| e.to_string())
+            })
+            .await?;
+            let mut stages = stages.lock().await;
+            match job {
+                Ok(job) => {
+                    let job_id = registry.lock().await.add(job);
+                    log::info!("Started stage '{}' as job {}", stages[i].name, job_id);
+                    stages[i].job_id = Some(job_id);
+                    stages[i].state = StageState::Running;
+                }
+                Err(e) => {
+                    log::error!("Stage '{}' failed to start: {}", stages[i].name, e);
+                    stages[i].state = StageState::Failed;
+                }
+            }
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("mr-pipeline-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.to_string_lossy().to_string()
+    }
+
+    async fn wait_for(
+        stages: &Mutex<Vec<StageStatus>>,
+        i: usize,
+        state: StageState,
+    ) -> StageStatus {
+        for _ in 0..50 {
+            let stage = stages.lock().await[i].clone();
+            if stage.state == state {
+                return stage;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("stage {} never became {}", i, state);
+    }
+
+    fn parse(stages: &str) -> Result<PipelineSpec, String> {
+        let mut pipeline: PipelineSpec = toml::from_str(stages).map_err(|e| e.to_string())?;
+        pipeline.resolve()?;
+        Ok(pipeline)
+    }
+
+    #[test]
+    fn stages_read_parquet_outputs_as_parquet() {
+        let pipeline = parse(
+            "[[stages]]\nname = \"a\"\napp = \"wc\"\ninputs = [\"in\"]\noutput_dir = \"a\"\n\
+             output_format = \"parquet:int64\"\n\n\
+             [[stages]]\nname = \"b\"\napp = \"wc\"\ninputs = [\"job:a\"]\noutput_dir = \"b\"\n",
+        )
+        .unwrap();
+        assert_eq!(pipeline.stages[1].inputs, ["a/mr-out-*"]);
+        assert_eq!(pipeline.stages[1].input_format, InputFormat::Parquet);
+
+        let err = parse(
+            "[[stages]]\nname = \"a\"\napp = \"wc\"\ninputs = [\"in\"]\noutput_dir = \"a\"\n\
+             output_format = \"parquet:int64\"\n\n\
+             [[stages]]\nname = \"b\"\napp = \"wc\"\ninputs = [\"in\"]\noutput_dir = \"b\"\n\n\
+             [[stages]]\nname = \"c\"\napp = \"wc\"\ninputs = [\"job:a\", \"job:b\"]\n\
+             output_dir = \"c\"\n",
+        )
+        .unwrap_err();
+        assert!(err.contains("both parquet and text"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn resume_reruns_stages_downstream_of_a_rerun_stage() {
+        let dir = temp_dir("resume");
+        fs::write(format!("{}/input.txt", dir), "a b\n").unwrap();
+        let path = format!("{}/pipeline.toml", dir);
+        fs::write(
+            &path,
+            format!(
+                "[[stages]]\nname = \"a\"\napp = \"wc\"\ninputs = [\"{dir}/input.txt\"]\n\
+                 output_dir = \"{dir}/a\"\n\n\
+                 [[stages]]\nname = \"b\"\napp = \"wc\"\ndepends_on = [\"a\"]\n\
+                 output_dir = \"{dir}/b\"\n"
+            ),
+        )
+        .unwrap();
+        let pipeline = PipelineSpec::from_file(Path::new(&path)).unwrap();
+
+        // `a` was committed with a different spec, `b` with its current one
+        let mut old_a = pipeline.stages[0].clone();
+        old_a.n_reduce += 1;
+        for (spec, saved) in [
+            (&pipeline.stages[0], &old_a),
+            (&pipeline.stages[1], &pipeline.stages[1]),
+        ] {
+            fs::create_dir_all(&spec.output_dir).unwrap();
+            fs::write(format!("{}/_SUCCESS", spec.output_dir), "").unwrap();
+            fs::write(format!("{}/_job.toml", spec.output_dir), saved.to_toml()).unwrap();
+            fs::write(format!("{}/mr-out-0", spec.output_dir), "a 1\n").unwrap();
+        }
+        assert!(!is_complete(&pipeline.stages[0]));
+        assert!(is_complete(&pipeline.stages[1]));
+
+        let registry = Arc::new(Mutex::new(JobRegistry::new()));
+        let stages = Arc::new(Mutex::new(pipeline.statuses()));
+        let run = tokio::spawn(run_pipeline(
+            pipeline,
+            Arc::clone(&registry),
+            Arc::clone(&stages),
+            true,
+        ));
+
+        // stand in for the workers: `a` runs again and completes
+        let a = wait_for(&stages, 0, StageState::Running).await;
+        registry
+            .lock()
+            .await
+            .jobs
+            .get_mut(&a.job_id.unwrap())
+            .unwrap()
+            .phase = Phase::Done;
+        let b = wait_for(&stages, 1, StageState::Running).await;
+        assert!(b.job_id.is_some());
+
+        run.abort();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
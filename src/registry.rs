@@ -0,0 +1,241 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::Notify;
+
+use crate::master::Master;
+use crate::rpc::{AttemptRef, Request, Response, TaskProgress};
+
+/// The jobs served by one master process. Workers form a single pool: each task request
+/// is handed the next runnable task of any unfinished job, oldest job first.
+pub struct JobRegistry {
+    pub jobs: BTreeMap<u32, Master>,
+    pub open: bool, // more jobs may be added; workers idle instead of exiting when all are done
+    next_job_id: u32,
+    pub workers: BTreeMap<u32, WorkerInfo>, // registered workers by id
+    pub worker_lease: Duration,             // workers without a heartbeat for this long are expired
+    next_worker_id: u32,
+    pub tasks_changed: Arc<Notify>, // wakes workers waiting for a task
+    pub reserved_outputs: BTreeSet<PathBuf>, // output dirs of submitted jobs still being built
+}
+
+pub struct WorkerInfo {
+    pub name: String,
+    pub slots: u32, // tasks the worker runs at once
+    pub last_heartbeat: Instant,
+}
+
+impl WorkerInfo {
+    /// How the worker is named in task attempts: its name and id.
+    pub fn label(&self, worker_id: u32) -> String {
+        format!("{}#{}", self.name, worker_id)
+    }
+
+    /// The worker name in a label made by `label`.
+    pub fn name_of(label: &str) -> &str {
+        label.rsplit_once('#').map_or(label, |(name, _)| name)
+    }
+}
+
+impl JobRegistry {
+    pub fn new() -> JobRegistry {
+        JobRegistry {
+            jobs: BTreeMap::new(),
+            open: true,
+            next_job_id: 0,
+            workers: BTreeMap::new(),
+            worker_lease: Duration::from_secs(10),
+            next_worker_id: 0,
+            tasks_changed: Arc::new(Notify::new()),
+            reserved_outputs: BTreeSet::new(),
+        }
+    }
+
+    /// A closed registry serving just `master`, as a single-job run does.
+    pub fn single(master: Master) -> JobRegistry {
+        let mut registry = JobRegistry::new();
+        registry.add(master);
+        registry.open = false;
+        registry
+    }
+
+    /// Registers a job and returns its id.
+    pub fn add(&mut self, mut master: Master) -> u32 {
+        let job_id = self.next_job_id;
+        self.next_job_id += 1;
+        master.job_id = job_id;
+        self.jobs.insert(job_id, master);
+        self.tasks_changed.notify_waiters();
+        job_id
+    }
+
+    /// A job by id, or the most recently submitted job.
+    pub fn get(&self, job_id: Option<u32>) -> Option<&Master> {
+        match job_id {
+            Some(id) => self.jobs.get(&id),
+            None => self.jobs.values().next_back(),
+        }
+    }
+
+    /// Cancels a job. Fails if the job is unknown or already finished.
+    pub fn cancel(&mut self, job_id: u32) -> Result<(), String> {
+        let job = self
+            .jobs
+            .get_mut(&job_id)
+            .ok_or_else(|| format!("no job {}", job_id))?;
+        if !job.cancel() {
+            return Err(format!("job {} already {}", job_id, job.phase));
+        }
+        self.tasks_changed.notify_waiters();
+        Ok(())
+    }
+
+    pub fn all_done(&self) -> bool {
+        self.jobs.values().all(|job| job.phase.is_finished())
+    }
+
+    /// Registers a worker with `slots` task slots and returns its id.
+    pub fn register_worker(&mut self, name: &str, slots: u32) -> u32 {
+        let worker_id = self.next_worker_id;
+        self.next_worker_id += 1;
+        self.workers.insert(
+            worker_id,
+            WorkerInfo {
+                name: name.to_string(),
+                slots,
+                last_heartbeat: Instant::now(),
+            },
+        );
+        log::info!(
+            "Registered worker {} as {} with {} slots",
+            name,
+            worker_id,
+            slots
+        );
+        worker_id
+    }
+
+    /// Renews a worker's lease and records the progress of its running attempts.
+    /// Returns the attempts the worker should abort, or None if it is not registered.
+    pub fn heartbeat(
+        &mut self,
+        worker_id: u32,
+        progress: &[TaskProgress],
+    ) -> Option<Vec<AttemptRef>> {
+        let worker = self.workers.get_mut(&worker_id)?;
+        worker.last_heartbeat = Instant::now();
+        let mut abort = Vec::new();
+        for p in progress {
+            match self.jobs.get_mut(&p.attempt.job_id) {
+                Some(job) if !job.should_abort(&p.attempt) => job.record_progress(p),
+                _ => abort.push(p.attempt.clone()),
+            }
+        }
+        Some(abort)
+    }
+
+    /// Expires workers whose lease ran out and reschedules their in-flight tasks.
+    pub fn expire_workers(&mut self) {
+        let lease = self.worker_lease;
+        let expired: Vec<u32> = self
+            .workers
+            .iter()
+            .filter(|(_, w)| w.last_heartbeat.elapsed() > lease)
+            .map(|(id, _)| *id)
+            .collect();
+        if !expired.is_empty() {
+            self.tasks_changed.notify_waiters();
+        }
+        for worker_id in expired {
+            if let Some(worker) = self.workers.remove(&worker_id) {
+                let label = worker.label(worker_id);
+                log::warn!("Worker {} missed its heartbeat lease, expiring", label);
+                for job in self.jobs.values_mut() {
+                    if !job.phase.is_finished() {
+                        job.release_worker(&label);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Attempts running on a worker, over all jobs.
+    pub fn running_attempts(&self, worker_id: u32) -> u32 {
+        let Some(worker) = self.workers.get(&worker_id) else {
+            return 0;
+        };
+        let label = worker.label(worker_id);
+        self.jobs
+            .values()
+            .map(|job| job.running_attempts(&label) as u32)
+            .sum()
+    }
+
+    /// Hands a registered worker the next runnable task. Unregistered workers and workers
+    /// with every slot busy get none.
+    pub fn get_task(&mut self, worker_id: u32) -> Response {
+        let free = self
+            .workers
+            .get(&worker_id)
+            .filter(|w| self.running_attempts(worker_id) < w.slots);
+        if let Some(worker) = free.map(|w| w.label(worker_id)) {
+            let names: BTreeSet<&str> = self.workers.values().map(|w| w.name.as_str()).collect();
+            for job in self.jobs.values_mut() {
+                if job.phase.is_finished() {
+                    continue;
+                }
+                job.limit_blacklist(&names);
+                if let task @ Response::Task { .. } = job.handle_request(Request::GetTask {
+                    worker: worker.clone(),
+                }) {
+                    return task;
+                }
+            }
+        }
+        if !self.open && self.all_done() {
+            Response::Exit
+        } else {
+            Response::NoTask
+        }
+    }
+
+    /// Routes a worker's completion report to its job. Reports for unknown jobs are
+    /// answered with `Abort`.
+    pub fn report(&mut self, job_id: u32, req: Request) -> Response {
+        // a finished or failed task may unblock others, or re-queue itself
+        self.tasks_changed.notify_waiters();
+        match self.jobs.get_mut(&job_id) {
+            Some(job) => job.handle_request(req),
+            None => {
+                log::warn!("Dropping report for unknown job {}", job_id);
+                Response::Abort
+            }
+        }
+    }
+
+    pub fn health_check(&mut self) {
+        self.tasks_changed.notify_waiters();
+        for job in self.jobs.values_mut() {
+            if !job.phase.is_finished()
+                && let Some(timeout) = job.speculation.timeout
+            {
+                job.health_check(timeout);
+            }
+        }
+    }
+
+    /// Path of a cache file with the given content hash, from whichever job shipped it.
+    pub fn cache_source(&self, hash: &str) -> Option<String> {
+        self.jobs
+            .values()
+            .find_map(|job| job.cache_sources.get(hash).cloned())
+    }
+}
+
+impl Default for JobRegistry {
+    fn default() -> Self {
+        JobRegistry::new()
+    }
+}
@@ -1,18 +1,35 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, SystemTime};
 
 use serde::{Deserialize, Serialize};
 
+use crate::format::{InputFormat, OutputFormat};
+use crate::models::Partitioner;
+
 // worker --> Master
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Request {
-    GetTask,
+    GetTask {
+        worker: String, // who is asking, for attempt history
+    },
     MapDone {
         task_id: u32,
+        attempt_id: u32,
         files: HashMap<u32, String>,
+        counters: BTreeMap<String, i64>,
+        skipped: Vec<(u64, u64)>, // record ranges skipped in skip mode
     },
     ReduceDone {
         task_id: u32,
+        attempt_id: u32,
+        counters: BTreeMap<String, i64>,
+    },
+    Failed {
+        task_type: TaskType,
+        task_id: u32,
+        attempt_id: u32,
+        error: String,
     },
 }
 
@@ -34,19 +51,147 @@ pub enum TaskStatus {
     Completed,
 }
 
+/// One execution of a task on a worker.
+#[derive(Debug, Clone)]
+pub struct Attempt {
+    pub attempt_id: u32,
+    pub worker: String,
+    pub started: SystemTime,
+    pub duration: Option<Duration>, // set once the attempt ends
+    pub backup: bool,               // speculative copy of a slow attempt
+    pub outcome: AttemptOutcome,
+    pub progress: f64,         // last fraction reported by the worker
+    pub error: Option<String>, // why a failed attempt failed
+}
+
+/// Identifies one attempt of a task across jobs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttemptRef {
+    pub job_id: u32,
+    pub task_type: TaskType,
+    pub task_id: u32,
+    pub attempt_id: u32,
+}
+
+/// Progress of a running attempt, as reported in a worker heartbeat.
+#[derive(Debug, Clone)]
+pub struct TaskProgress {
+    pub attempt: AttemptRef,
+    pub fraction: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AttemptOutcome {
+    Running,
+    Succeeded,
+    Superseded, // finished after another attempt of the same task had won
+    TimedOut,
+    Lost,   // its worker stopped sending heartbeats
+    Killed, // told to stop: another attempt won, or the job was cancelled
+    Failed, // the worker reported an error
+}
+
+impl std::fmt::Display for TaskType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TaskType::Map => write!(f, "map"),
+            TaskType::Reduce => write!(f, "reduce"),
+            TaskType::Idle => write!(f, "idle"),
+            TaskType::Exit => write!(f, "exit"),
+        }
+    }
+}
+
+impl std::fmt::Display for AttemptOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AttemptOutcome::Running => write!(f, "running"),
+            AttemptOutcome::Succeeded => write!(f, "succeeded"),
+            AttemptOutcome::Superseded => write!(f, "superseded"),
+            AttemptOutcome::TimedOut => write!(f, "timed-out"),
+            AttemptOutcome::Lost => write!(f, "worker-lost"),
+            AttemptOutcome::Killed => write!(f, "killed"),
+            AttemptOutcome::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+impl std::fmt::Display for TaskStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TaskStatus::Idle => write!(f, "idle"),
+            TaskStatus::InProgress { .. } => write!(f, "in-progress"),
+            TaskStatus::Completed => write!(f, "completed"),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Phase {
     Map,
     Reduce,
     Done,
+    Cancelled,
+    Failed, // a task failed too often
+}
+
+impl Phase {
+    /// Done, cancelled or failed: the job hands out no more tasks.
+    pub fn is_finished(self) -> bool {
+        matches!(self, Phase::Done | Phase::Cancelled | Phase::Failed)
+    }
+}
+
+impl std::fmt::Display for Phase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Phase::Map => write!(f, "map"),
+            Phase::Reduce => write!(f, "reduce"),
+            Phase::Done => write!(f, "done"),
+            Phase::Cancelled => write!(f, "cancelled"),
+            Phase::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+/// A side file distributed to every task, identified by its content hash.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CacheFile {
+    pub name: String,
+    pub hash: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct TaskData {
+    pub job_id: u32,              // job the task belongs to
     pub task_id: u32,             // unique id
     pub input_files: Vec<String>, // files for the task to process
     pub n_reduce: u32,            // total number of reduce partitions
     pub output_path: String,      // where to write output files
+    pub row_group: Option<u32>,   // parquet row group of the input split
+    pub input_format: InputFormat,
+    pub output_format: OutputFormat,
+    pub app: String, // registered application to run
+    pub partitioner: Partitioner,
+    pub attempt_id: u32, // distinguishes backup and retried attempts
+    pub config: BTreeMap<String, String>, // user key/values from the job spec
+    pub cache_files: Vec<CacheFile>,
+    pub tag: Option<String>,    // dataset tag of a map split
+    pub mapper: Option<String>, // map function for the split, if not the app's own
+    pub datasets: Vec<String>,  // tags of all input datasets
+    pub skip_bad_records: bool, // map in skip mode, after repeated failures
+}
+
+impl TaskData {
+    /// The attempt this task data launches.
+    pub fn attempt(&self, task_type: TaskType) -> AttemptRef {
+        AttemptRef {
+            job_id: self.job_id,
+            task_type,
+            task_id: self.task_id,
+            attempt_id: self.attempt_id,
+        }
+    }
 }
 
 // master -> worker
@@ -54,8 +199,9 @@ pub struct TaskData {
 pub enum Response {
     Task {
         task_type: TaskType,
-        task_data: TaskData,
+        task_data: Box<TaskData>,
     },
     NoTask,
     Exit,
+    Abort, // the reported task's job was cancelled; discard its output
 }
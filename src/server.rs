@@ -1,28 +1,107 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, UNIX_EPOCH};
 
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Notify};
+use tokio::time::Instant;
 use tonic::{Response, Status, transport::Server};
 
+use crate::job::JobSpec;
 use crate::master::Master;
-use crate::rpc::{Phase, Request};
+use crate::pipeline::StageStatus;
+use crate::registry::JobRegistry;
+use crate::rpc::{Attempt, AttemptRef, Request, TaskProgress, TaskStatus, TaskType};
 
 pub mod mr {
     tonic::include_proto!("mapreduce");
 }
 
 pub struct MapReducer {
-    pub master: Arc<Mutex<Master>>,
+    pub registry: Arc<Mutex<JobRegistry>>,
+    pub stages: Arc<Mutex<Vec<StageStatus>>>, // empty unless serving a pipeline
+    pub tasks_changed: Arc<Notify>,           // the registry's, awaited without its lock
 }
 
+/// Longest a `GetTask` call is held open, whatever the worker asks for.
+const MAX_TASK_WAIT: Duration = Duration::from_secs(30);
+/// How often a held `GetTask` call looks again for tasks that became runnable with time
+/// alone, such as backups and workers leaving a blacklist.
+const TASK_RECHECK: Duration = Duration::from_secs(1);
+
 #[tonic::async_trait]
 impl mr::map_reduce_server::MapReduce for MapReducer {
+    async fn register_worker(
+        &self,
+        request: tonic::Request<mr::RegisterWorkerRequest>,
+    ) -> Result<Response<mr::RegisterWorkerResponse>, Status> {
+        let req = request.into_inner();
+        let mut name = req.name;
+        if name.is_empty() {
+            name = "worker".to_string();
+        }
+        let mut registry = self.registry.lock().await;
+        let worker_id = registry.register_worker(&name, req.slots.max(1));
+        let lease = registry.worker_lease;
+        Ok(Response::new(mr::RegisterWorkerResponse {
+            worker_id,
+            heartbeat_interval_ms: (lease / 3).as_millis() as u64,
+            lease_ms: lease.as_millis() as u64,
+        }))
+    }
+
+    async fn heartbeat(
+        &self,
+        request: tonic::Request<mr::HeartbeatRequest>,
+    ) -> Result<Response<mr::HeartbeatResponse>, Status> {
+        let req = request.into_inner();
+        let progress: Vec<TaskProgress> = req
+            .tasks
+            .into_iter()
+            .map(|t| TaskProgress {
+                attempt: attempt_ref(t.attempt.unwrap_or_default()),
+                fraction: t.fraction,
+            })
+            .collect();
+        let abort = self
+            .registry
+            .lock()
+            .await
+            .heartbeat(req.worker_id, &progress);
+        Ok(Response::new(mr::HeartbeatResponse {
+            registered: abort.is_some(),
+            abort: abort
+                .into_iter()
+                .flatten()
+                .map(|a| mr::AttemptRef {
+                    job_id: a.job_id,
+                    task_type: a.task_type.to_string(),
+                    task_id: a.task_id,
+                    attempt_id: a.attempt_id,
+                })
+                .collect(),
+        }))
+    }
+
     async fn get_task(
         &self,
-        _request: tonic::Request<mr::Empty>,
+        request: tonic::Request<mr::GetTaskRequest>,
     ) -> Result<Response<mr::TaskResponse>, Status> {
-        let mut master = self.master.lock().await;
-        let resp = master.handle_request(Request::GetTask);
+        let req = request.into_inner();
+        let deadline = Instant::now() + Duration::from_millis(req.wait_ms).min(MAX_TASK_WAIT);
+        let resp = loop {
+            // listen before looking, so a change made in between still wakes us
+            let notified = self.tasks_changed.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            let resp = self.registry.lock().await.get_task(req.worker_id);
+            let now = Instant::now();
+            if !matches!(resp, crate::rpc::Response::NoTask) || now >= deadline {
+                break resp;
+            }
+            let _ = tokio::time::timeout((deadline - now).min(TASK_RECHECK), notified).await;
+        };
 
         let response = match resp {
             crate::rpc::Response::Task {
@@ -37,18 +116,39 @@ impl mr::map_reduce_server::MapReduce for MapReducer {
                 };
                 mr::TaskResponse {
                     task_type: tt.to_string(),
+                    job_id: task_data.job_id,
                     task_id: task_data.task_id,
                     input_files: task_data.input_files,
                     n_reduce: task_data.n_reduce,
                     output_path: task_data.output_path,
+                    row_group: task_data.row_group,
+                    input_format: task_data.input_format.to_string(),
+                    output_format: task_data.output_format.to_string(),
+                    app: task_data.app,
+                    partitioner: task_data.partitioner.to_string(),
+                    attempt_id: task_data.attempt_id,
+                    config: task_data.config.into_iter().collect(),
+                    cache_files: task_data
+                        .cache_files
+                        .into_iter()
+                        .map(|f| mr::CacheFile {
+                            name: f.name,
+                            hash: f.hash,
+                        })
+                        .collect(),
+                    tag: task_data.tag,
+                    mapper: task_data.mapper,
+                    datasets: task_data.datasets,
+                    skip_bad_records: task_data.skip_bad_records,
                 }
             }
-            crate::rpc::Response::NoTask => mr::TaskResponse {
+            crate::rpc::Response::NoTask | crate::rpc::Response::Abort => mr::TaskResponse {
                 task_type: "idle".to_string(),
                 task_id: 0,
                 input_files: vec![],
                 n_reduce: 0,
                 output_path: String::new(),
+                ..Default::default()
             },
             crate::rpc::Response::Exit => mr::TaskResponse {
                 task_type: "exit".to_string(),
@@ -56,6 +156,7 @@ impl mr::map_reduce_server::MapReduce for MapReducer {
                 input_files: vec![],
                 n_reduce: 0,
                 output_path: String::new(),
+                ..Default::default()
             },
         };
 
@@ -65,57 +166,399 @@ impl mr::map_reduce_server::MapReduce for MapReducer {
     async fn map_done(
         &self,
         request: tonic::Request<mr::MapDoneRequest>,
-    ) -> Result<Response<mr::Empty>, Status> {
+    ) -> Result<Response<mr::DoneResponse>, Status> {
         let req = request.into_inner();
         let files: std::collections::HashMap<u32, String> = req.files.into_iter().collect();
 
-        let mut master = self.master.lock().await;
-        master.handle_request(Request::MapDone {
-            task_id: req.task_id,
-            files,
-        });
+        let mut registry = self.registry.lock().await;
+        let resp = registry.report(
+            req.job_id,
+            Request::MapDone {
+                task_id: req.task_id,
+                attempt_id: req.attempt_id,
+                files,
+                counters: req.counters.into_iter().collect(),
+                skipped: req.skipped.iter().map(|r| (r.start, r.end)).collect(),
+            },
+        );
+
+        Ok(Response::new(mr::DoneResponse {
+            abort: matches!(resp, crate::rpc::Response::Abort),
+        }))
+    }
 
+    async fn report_failure(
+        &self,
+        request: tonic::Request<mr::ReportFailureRequest>,
+    ) -> Result<Response<mr::Empty>, Status> {
+        let req = request.into_inner();
+        let attempt = attempt_ref(req.attempt.unwrap_or_default());
+        self.registry.lock().await.report(
+            attempt.job_id,
+            Request::Failed {
+                task_type: attempt.task_type,
+                task_id: attempt.task_id,
+                attempt_id: attempt.attempt_id,
+                error: req.error,
+            },
+        );
         Ok(Response::new(mr::Empty {}))
     }
 
     async fn reduce_done(
         &self,
         request: tonic::Request<mr::ReduceDoneRequest>,
-    ) -> Result<Response<mr::Empty>, Status> {
+    ) -> Result<Response<mr::DoneResponse>, Status> {
         let req = request.into_inner();
 
-        let mut master = self.master.lock().await;
-        master.handle_request(Request::ReduceDone {
-            task_id: req.task_id,
-        });
+        let mut registry = self.registry.lock().await;
+        let resp = registry.report(
+            req.job_id,
+            Request::ReduceDone {
+                task_id: req.task_id,
+                attempt_id: req.attempt_id,
+                counters: req.counters.into_iter().collect(),
+            },
+        );
+
+        Ok(Response::new(mr::DoneResponse {
+            abort: matches!(resp, crate::rpc::Response::Abort),
+        }))
+    }
+
+    async fn fetch_cache_file(
+        &self,
+        request: tonic::Request<mr::CacheFileRequest>,
+    ) -> Result<Response<mr::CacheFileResponse>, Status> {
+        let hash = request.into_inner().hash;
+        let path = self.registry.lock().await.cache_source(&hash);
+        let path = path.ok_or_else(|| Status::not_found(format!("no cache file {}", hash)))?;
+
+        let content = tokio::fs::read(&path)
+            .await
+            .map_err(|e| Status::internal(format!("cannot read {}: {}", path, e)))?;
+        if crate::cache::content_hash(&content) != hash {
+            return Err(Status::failed_precondition(format!(
+                "{} changed since the job was submitted",
+                path
+            )));
+        }
+        Ok(Response::new(mr::CacheFileResponse { content }))
+    }
+
+    async fn get_job_status(
+        &self,
+        request: tonic::Request<mr::JobStatusRequest>,
+    ) -> Result<Response<mr::JobStatusResponse>, Status> {
+        let job_id = request.into_inner().job_id;
+        let stages = self
+            .stages
+            .lock()
+            .await
+            .iter()
+            .map(|s| mr::StageStatus {
+                name: s.name.clone(),
+                output_dir: s.output_dir.clone(),
+                state: s.state.to_string(),
+                depends_on: s.depends_on.clone(),
+                job_id: s.job_id,
+            })
+            .collect();
+        let registry = self.registry.lock().await;
+        let Some(master) = registry.get(job_id) else {
+            if let Some(id) = job_id {
+                return Err(Status::not_found(format!("no job {}", id)));
+            }
+            return Ok(Response::new(mr::JobStatusResponse {
+                phase: "idle".to_string(),
+                stages,
+                ..Default::default()
+            }));
+        };
+        let mut tasks = task_infos("map", &master.map_task, &master.map_history);
+        let (backups_launched, backups_won) = master.backup_stats();
+        tasks.extend(task_infos(
+            "reduce",
+            &master.reduce_task,
+            &master.reduce_history,
+        ));
+        Ok(Response::new(mr::JobStatusResponse {
+            phase: master.phase.to_string(),
+            counters: master.counters.clone().into_iter().collect(),
+            spec: master
+                .spec
+                .as_ref()
+                .map(|s| s.to_toml())
+                .unwrap_or_default(),
+            stages,
+            job_id: Some(master.job_id),
+            name: master.name(),
+            map_tasks: Some(task_counts(&master.map_task)),
+            reduce_tasks: Some(task_counts(&master.reduce_task)),
+            tasks,
+            eta_secs: master.eta().map(|eta| eta.as_secs()),
+            backups_launched,
+            backups_won,
+            failure: master.failure.clone(),
+            blacklisted: master
+                .blacklisted_workers()
+                .into_iter()
+                .map(|(worker, remaining)| mr::BlacklistedWorker {
+                    worker,
+                    remaining_secs: remaining.as_secs(),
+                })
+                .collect(),
+        }))
+    }
+
+    async fn submit_job(
+        &self,
+        request: tonic::Request<mr::SubmitJobRequest>,
+    ) -> Result<Response<mr::SubmitJobResponse>, Status> {
+        let spec = JobSpec::from_toml(&request.into_inner().spec)
+            .map_err(|e| Status::invalid_argument(format!("invalid job spec: {}", e)))?;
+        if spec.iterate.is_some() {
+            return Err(Status::invalid_argument(
+                "iterative jobs must be run with `master run`",
+            ));
+        }
 
+        // the output dir is reserved while the job is built, which writes to it
+        let output = output_key(&spec.output_dir);
+        {
+            let mut registry = self.registry.lock().await;
+            check_submission(&registry, &output)?;
+            registry.reserved_outputs.insert(output.clone());
+        }
+        // expanding inputs and hashing cache files blocks, so it runs without the lock
+        let built = tokio::task::spawn_blocking(move || {
+            Master::from_spec(spec).map_err(|e| Status::invalid_argument(e.to_string()))
+        })
+        .await;
+
+        let mut registry = self.registry.lock().await;
+        registry.reserved_outputs.remove(&output);
+        let master = built.map_err(|e| Status::internal(e.to_string()))??;
+        check_submission(&registry, &output)?;
+        let name = master.name();
+        let job_id = registry.add(master);
+        log::info!("Accepted job {} ({})", job_id, name);
+        Ok(Response::new(mr::SubmitJobResponse { job_id }))
+    }
+
+    async fn list_jobs(
+        &self,
+        _request: tonic::Request<mr::Empty>,
+    ) -> Result<Response<mr::ListJobsResponse>, Status> {
+        let registry = self.registry.lock().await;
+        let completed = |tasks: &HashMap<u32, TaskStatus>| {
+            tasks
+                .values()
+                .filter(|s| matches!(s, TaskStatus::Completed))
+                .count() as u32
+        };
+        let jobs = registry
+            .jobs
+            .values()
+            .map(|job| mr::JobSummary {
+                job_id: job.job_id,
+                name: job.name(),
+                app: job.app.clone(),
+                phase: job.phase.to_string(),
+                maps_done: completed(&job.map_task),
+                maps_total: job.map_task.len() as u32,
+                reduces_done: completed(&job.reduce_task),
+                reduces_total: job.n_reduce,
+            })
+            .collect();
+        Ok(Response::new(mr::ListJobsResponse { jobs }))
+    }
+
+    async fn list_workers(
+        &self,
+        _request: tonic::Request<mr::Empty>,
+    ) -> Result<Response<mr::ListWorkersResponse>, Status> {
+        let registry = self.registry.lock().await;
+        let workers = registry
+            .workers
+            .iter()
+            .map(|(id, w)| mr::WorkerSummary {
+                worker_id: *id,
+                name: w.name.clone(),
+                slots: w.slots,
+                running: registry.running_attempts(*id),
+                heartbeat_age_ms: w.last_heartbeat.elapsed().as_millis() as u64,
+            })
+            .collect();
+        Ok(Response::new(mr::ListWorkersResponse { workers }))
+    }
+
+    async fn cancel_job(
+        &self,
+        request: tonic::Request<mr::CancelJobRequest>,
+    ) -> Result<Response<mr::Empty>, Status> {
+        let job_id = request.into_inner().job_id;
+        self.registry
+            .lock()
+            .await
+            .cancel(job_id)
+            .map_err(Status::failed_precondition)?;
         Ok(Response::new(mr::Empty {}))
     }
 }
 
+/// Whether the registry takes a new job writing to `output`, a key from `output_key`.
+fn check_submission(registry: &JobRegistry, output: &Path) -> Result<(), Status> {
+    if !registry.open {
+        return Err(Status::failed_precondition("master is not accepting jobs"));
+    }
+    if registry.reserved_outputs.contains(output) {
+        return Err(Status::already_exists(format!(
+            "another job is already writing to {}",
+            output.display()
+        )));
+    }
+    if let Some(other) = registry
+        .jobs
+        .values()
+        .find(|job| !job.phase.is_finished() && output_key(&job.output) == output)
+    {
+        return Err(Status::already_exists(format!(
+            "job {} is already writing to {}",
+            other.job_id,
+            output.display()
+        )));
+    }
+    Ok(())
+}
+
+/// An output dir as an absolute path, with its parent resolved, so that different
+/// spellings of the same dir compare equal. The dir itself need not exist yet.
+fn output_key(dir: &str) -> PathBuf {
+    let path = std::path::absolute(dir).unwrap_or_else(|_| PathBuf::from(dir));
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => parent
+            .canonicalize()
+            .map(|parent| parent.join(name))
+            .unwrap_or(path),
+        _ => path,
+    }
+}
+
+fn attempt_ref(a: mr::AttemptRef) -> AttemptRef {
+    AttemptRef {
+        job_id: a.job_id,
+        task_type: match a.task_type.as_str() {
+            "reduce" => TaskType::Reduce,
+            _ => TaskType::Map,
+        },
+        task_id: a.task_id,
+        attempt_id: a.attempt_id,
+    }
+}
+
+fn task_counts(tasks: &HashMap<u32, TaskStatus>) -> mr::TaskCounts {
+    let mut counts = mr::TaskCounts::default();
+    for status in tasks.values() {
+        match status {
+            TaskStatus::Idle => counts.idle += 1,
+            TaskStatus::InProgress { .. } => counts.in_progress += 1,
+            TaskStatus::Completed => counts.completed += 1,
+        }
+    }
+    counts
+}
+
+fn task_infos(
+    task_type: &str,
+    tasks: &HashMap<u32, TaskStatus>,
+    history: &HashMap<u32, Vec<Attempt>>,
+) -> Vec<mr::TaskInfo> {
+    let mut ids: Vec<u32> = tasks.keys().copied().collect();
+    ids.sort();
+    ids.into_iter()
+        .map(|id| mr::TaskInfo {
+            task_type: task_type.to_string(),
+            task_id: id,
+            state: tasks[&id].to_string(),
+            attempts: history
+                .get(&id)
+                .into_iter()
+                .flatten()
+                .map(|a| mr::AttemptInfo {
+                    attempt_id: a.attempt_id,
+                    worker: a.worker.clone(),
+                    start_unix_ms: a
+                        .started
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_millis() as u64,
+                    duration_ms: a
+                        .duration
+                        .or_else(|| a.started.elapsed().ok())
+                        .unwrap_or_default()
+                        .as_millis() as u64,
+                    backup: a.backup,
+                    outcome: a.outcome.to_string(),
+                    progress: a.progress,
+                    error: a.error.clone(),
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+pub struct ServerConfig {
+    pub addr: SocketAddr,
+    pub health_check_interval: Duration, // how often to look for stuck tasks
+    pub worker_lease: Duration,          // workers silent for this long are expired
+}
+
 pub async fn run_server(
-    input_files: Vec<String>,
-    n_reduce: u32,
-    output_path: String,
-    addr: String,
+    master: Master,
+    config: ServerConfig,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let master = Arc::new(Mutex::new(Master::new(input_files, n_reduce, output_path)));
+    let registry = JobRegistry::single(master);
+    serve(Arc::new(Mutex::new(registry)), Arc::default(), config).await
+}
 
-    let master_for_health = Arc::clone(&master);
+/// Serves a shared registry that jobs may be added to while serving, as workflows do.
+pub async fn serve(
+    registry: Arc<Mutex<JobRegistry>>,
+    stages: Arc<Mutex<Vec<StageStatus>>>,
+    config: ServerConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let tasks_changed = {
+        let mut registry = registry.lock().await;
+        registry.worker_lease = config.worker_lease;
+        Arc::clone(&registry.tasks_changed)
+    };
+    let registry_for_health = Arc::clone(&registry);
     tokio::spawn(async move {
+        // expiry runs at least twice per lease so a dead worker is noticed promptly
+        let tick = config.health_check_interval.min(config.worker_lease / 2);
+        let mut last_health_check = tokio::time::Instant::now();
         loop {
-            tokio::time::sleep(Duration::from_secs(10)).await;
-            let mut m = master_for_health.lock().await;
-            if matches!(m.phase, Phase::Done) {
+            tokio::time::sleep(tick).await;
+            let mut r = registry_for_health.lock().await;
+            if !r.open && r.all_done() {
                 log::info!("Health check: Job complete, stopping");
                 break;
             }
-            m.health_check(30);
+            r.expire_workers();
+            if last_health_check.elapsed() >= config.health_check_interval {
+                last_health_check = tokio::time::Instant::now();
+                r.health_check();
+            }
         }
     });
 
-    let mapreducer = MapReducer { master };
-    let addr = addr.parse()?;
+    let mapreducer = MapReducer {
+        registry,
+        stages,
+        tasks_changed,
+    };
+    let addr = config.addr;
 
     log::info!("gRPC Master listening on {}", addr);
 
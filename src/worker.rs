@@ -1,24 +1,25 @@
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
-use std::hash::Hasher;
+use std::fs::{self, read_to_string};
 use std::io::Write;
-use std::{
-    fs::{self, read_to_string},
-    hash::{DefaultHasher, Hash},
-};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::{Arc, Once};
 
-use crate::models::{KeyValue, Report};
+use crate::apps::{self, Reducer};
+use crate::format::{self, InputFormat, InputSplit};
+use crate::models::{
+    JobContext, JoinFunction, KeyValue, MapFunction, Progress, ReduceFunction, Report,
+};
 use crate::rpc::TaskData;
 use crate::rpc::TaskType;
 
 pub struct Worker {
     pub task_data: TaskData,
     pub task_type: TaskType,
-}
-
-fn reduce(key: String, values: Vec<u32>) -> String {
-    let val = values.iter().sum::<u32>();
-    format!("{} {}", key, val)
+    pub cache_files: BTreeMap<String, PathBuf>, // localized side files by name
+    pub progress: Arc<Progress>,                // read by the heartbeat while the task runs
 }
 
 impl Worker {
@@ -26,132 +27,474 @@ impl Worker {
         Worker {
             task_data: data,
             task_type: typo,
+            cache_files: BTreeMap::new(),
+            progress: Arc::default(),
+        }
+    }
+
+    /// Removes the files a finished task wrote, when its job was cancelled meanwhile.
+    pub fn discard(&self, report: &Report) {
+        let data = &self.task_data;
+        let mut files: Vec<String> = match report {
+            Report::MapDone { files, .. } => files.values().cloned().collect(),
+            _ => Vec::new(),
+        };
+        if matches!(self.task_type, TaskType::Reduce) || data.n_reduce == 0 {
+            files.push(
+                data.output_format
+                    .output_file(&data.output_path, data.task_id),
+            );
+        }
+        for file in files {
+            let _ = fs::remove_file(file);
+        }
+    }
+
+    /// Removes the temporary files of this attempt, when it is aborted or fails.
+    fn remove_temp_files(&self) {
+        let data = &self.task_data;
+        let mut files: Vec<String> = Vec::new();
+        if matches!(self.task_type, TaskType::Map) {
+            files.extend((0..data.n_reduce).map(|partition| {
+                format!(
+                    "{}/mr-{}-{}.{}.tmp",
+                    data.output_path, data.task_id, partition, data.attempt_id
+                )
+            }));
+        }
+        if matches!(self.task_type, TaskType::Reduce) || data.n_reduce == 0 {
+            let output = data
+                .output_format
+                .output_file(&data.output_path, data.task_id);
+            files.push(format!("{}.{}.tmp", output, data.attempt_id));
+        }
+        for file in files {
+            let _ = fs::remove_file(file);
+        }
+    }
+
+    /// Stops a task the master asked to abort, removing any temporary files it wrote.
+    fn abort(&self) -> Report {
+        let data = &self.task_data;
+        self.remove_temp_files();
+        log::info!(
+            "Aborted {} task {} attempt {}",
+            self.task_type,
+            data.task_id,
+            data.attempt_id
+        );
+        Report::Aborted
+    }
+
+    fn context(&self) -> JobContext {
+        let data = &self.task_data;
+        JobContext {
+            config: data.config.clone(),
+            task_id: data.task_id,
+            attempt_id: data.attempt_id,
+            partition: match self.task_type {
+                TaskType::Reduce => Some(data.task_id),
+                _ => None,
+            },
+            counters: RefCell::new(BTreeMap::new()),
+            cache_files: self.cache_files.clone(),
+            datasets: data.datasets.clone(),
+            tag: data.tag.clone(),
         }
     }
 
-    pub fn run(&self) -> Report {
+    /// Runs the task. A failed task leaves no temporary files behind; the error is
+    /// reported to the master, which retries the task elsewhere. Panics in app code fail
+    /// the task like any other error.
+    pub fn run(&self) -> Result<Report, Box<dyn std::error::Error + Send + Sync>> {
+        panic::catch_unwind(AssertUnwindSafe(|| self.execute()))
+            .unwrap_or_else(
+                |panic| Err(format!("task panicked: {}", panic_message(&*panic)).into()),
+            )
+            .inspect_err(|_| self.remove_temp_files())
+    }
+
+    fn execute(&self) -> Result<Report, Box<dyn std::error::Error + Send + Sync>> {
         match self.task_type {
-            TaskType::Idle => {
-                std::thread::sleep(std::time::Duration::from_secs(1));
-                Report::Exit
-            }
-            TaskType::Exit => Report::Exit,
+            TaskType::Idle | TaskType::Exit => Ok(Report::Exit),
             TaskType::Map => {
                 let data = &self.task_data;
-                let content = read_to_string(&data.input_files[0]).expect("Invalid File");
-                let kvs: Vec<KeyValue> = map(&data.input_files[0], content);
-                fs::create_dir_all(&data.output_path).expect("Failed to create_dir");
+                let app =
+                    apps::lookup(&data.app).ok_or_else(|| format!("unknown app '{}'", data.app))?;
+                let map = match &data.mapper {
+                    Some(name) => {
+                        apps::mapper(name).ok_or_else(|| format!("unknown mapper '{}'", name))?
+                    }
+                    None => app.map,
+                };
+                let split = InputSplit {
+                    path: data.input_files[0].clone(),
+                    row_group: data.row_group,
+                    tag: data.tag.clone(),
+                };
+                let content = format::read_split(&split, data.input_format)
+                    .map_err(|e| format!("cannot read split {}: {}", split.path, e))?;
+                // map progress: split read, mapped, then each partition written
+                self.progress.set(0.3);
+                if self.progress.is_aborted() {
+                    return Ok(self.abort());
+                }
+                let ctx = self.context();
+                ctx.incr("map_input_bytes", content.len() as i64);
+                let (kvs, skipped) = if data.skip_bad_records {
+                    map_skipping(&ctx, map, &data.input_files[0], &content, data.input_format)
+                } else {
+                    (map(&ctx, data.input_files[0].clone(), content), Vec::new())
+                };
+                ctx.incr("map_output_records", kvs.len() as i64);
+                self.progress.set(0.6);
+                if self.progress.is_aborted() {
+                    return Ok(self.abort());
+                }
+                fs::create_dir_all(&data.output_path)?;
+
+                // map-only job: map output is the final output, committed like a reduce partition
+                if data.n_reduce == 0 {
+                    let records: Vec<(String, String)> =
+                        kvs.into_iter().map(|kv| (kv.key, kv.value)).collect();
+                    let final_filename = data
+                        .output_format
+                        .output_file(&data.output_path, data.task_id);
+                    let temp_filename = format!("{}.{}.tmp", final_filename, data.attempt_id);
+                    format::write_output(&temp_filename, data.output_format, &records)
+                        .map_err(|e| format!("cannot write {}: {}", temp_filename, e))?;
+                    fs::rename(&temp_filename, &final_filename)?;
+                    return Ok(Report::MapDone {
+                        taskid: data.task_id,
+                        files: HashMap::new(),
+                        counters: ctx.counters.take(),
+                        skipped,
+                    });
+                }
 
-                let mut partitions: HashMap<u32, Vec<String>> = HashMap::new();
+                let mut partitions: HashMap<u32, Vec<(String, String)>> = HashMap::new();
                 for kv in kvs {
-                    let partition_id = ihash(&kv.key) % data.n_reduce;
-                    let value = format!("{},{};", kv.key, kv.value);
-                    partitions.entry(partition_id).or_default().push(value);
+                    let partition_id = data.partitioner.partition(&kv.key, data.n_reduce);
+                    partitions
+                        .entry(partition_id)
+                        .or_default()
+                        .push((kv.key, kv.value));
                 }
 
                 let mut files = HashMap::new();
-                for (partition_id, values) in partitions {
+                let n_partitions = partitions.len();
+                for (i, (partition_id, mut kvs)) in partitions.into_iter().enumerate() {
+                    if self.progress.is_aborted() {
+                        return Ok(self.abort());
+                    }
+                    if let Some(combine) = app.combine
+                        && data.tag.is_none()
+                    {
+                        kvs.sort_by(|a, b| a.0.cmp(&b.0));
+                        kvs = reduce_sorted(&ctx, combine, kvs, None);
+                        ctx.incr("combine_output_records", kvs.len() as i64);
+                    }
                     let temp_filename = format!(
-                        "{}/mr-{}-{}.tmp",
-                        data.output_path, data.task_id, partition_id
+                        "{}/mr-{}-{}.{}.tmp",
+                        data.output_path, data.task_id, partition_id, data.attempt_id
                     );
                     let final_filename =
                         format!("{}/mr-{}-{}", data.output_path, data.task_id, partition_id);
 
-                    let mut file =
-                        File::create(&temp_filename).expect("Unable to create temp file");
-                    for v in &values {
-                        writeln!(file, "{}", v).expect("Failed to write");
+                    let mut file = File::create(&temp_filename)
+                        .map_err(|e| format!("cannot create {}: {}", temp_filename, e))?;
+                    // one JSON-encoded [key, value] or [key, value, tag] record per line
+                    for (key, value) in &kvs {
+                        let line = match &data.tag {
+                            Some(tag) => serde_json::to_string(&(key, value, tag)),
+                            None => serde_json::to_string(&(key, value)),
+                        };
+                        writeln!(file, "{}", line?)?;
                     }
-                    file.flush().expect("Failed to flush");
+                    file.flush()?;
 
-                    fs::rename(&temp_filename, &final_filename)
-                        .expect("Failed to rename temp file");
+                    fs::rename(&temp_filename, &final_filename)?;
 
                     files.insert(partition_id, final_filename);
+                    self.progress
+                        .set(0.6 + 0.4 * (i + 1) as f64 / n_partitions as f64);
                 }
 
-                Report::MapDone {
+                Ok(Report::MapDone {
                     taskid: data.task_id,
                     files,
-                }
+                    counters: ctx.counters.take(),
+                    skipped,
+                })
             }
 
             TaskType::Reduce => {
                 let data = &self.task_data;
+                let app =
+                    apps::lookup(&data.app).ok_or_else(|| format!("unknown app '{}'", data.app))?;
 
-                fs::create_dir_all(&data.output_path).expect("Failed to create dir");
+                fs::create_dir_all(&data.output_path)?;
 
-                let mut all_kv: Vec<(String, u32)> = Vec::new();
+                let mut all_kv: Vec<(String, String, Option<String>)> = Vec::new();
 
-                for file in &data.input_files {
-                    let content = read_to_string(file).expect("Invalid file");
-                    for item in content.split(";") {
-                        if let Some((k, v)) = item.split_once(",") {
-                            if !k.is_empty() {
-                                all_kv.push((k.to_string(), v.parse().unwrap_or(0)));
+                // reduce progress: the first half reading inputs, the second merging records
+                for (i, file) in data.input_files.iter().enumerate() {
+                    let content =
+                        read_to_string(file).map_err(|e| format!("cannot read {}: {}", file, e))?;
+                    for line in content.lines() {
+                        let record: Vec<String> = serde_json::from_str(line).map_err(|e| {
+                            format!("invalid intermediate record in {}: {}", file, e)
+                        })?;
+                        let mut fields = record.into_iter();
+                        match (fields.next(), fields.next(), fields.next()) {
+                            (Some(k), Some(v), tag) => all_kv.push((k, v, tag)),
+                            _ => {
+                                return Err(format!(
+                                    "invalid intermediate record in {}: {}",
+                                    file, line
+                                )
+                                .into());
                             }
                         }
                     }
+                    self.progress
+                        .set(0.5 * (i + 1) as f64 / data.input_files.len() as f64);
+                    if self.progress.is_aborted() {
+                        return Ok(self.abort());
+                    }
                 }
 
-                all_kv.sort_by_key(|f| f.0.clone());
+                all_kv.sort_by(|a, b| a.0.cmp(&b.0));
 
-                let temp_filename = format!("{}/mr-out-{}.tmp", data.output_path, data.task_id);
-                let final_filename = format!("{}/mr-out-{}", data.output_path, data.task_id);
+                let ctx = self.context();
+                ctx.incr("reduce_input_records", all_kv.len() as i64);
+                let final_filename = data
+                    .output_format
+                    .output_file(&data.output_path, data.task_id);
+                let temp_filename = format!("{}.{}.tmp", final_filename, data.attempt_id);
 
-                let mut file = File::create(&temp_filename).expect("Unable to create temp file");
-
-                let mut i = 0;
-                while i < all_kv.len() {
-                    let key = all_kv[i].0.clone();
-                    let mut values = Vec::new();
-                    while i < all_kv.len() && key == all_kv[i].0 {
-                        values.push(all_kv[i].1);
-                        i += 1;
+                let records = match app.reduce {
+                    Reducer::Values(reduce) => {
+                        let kvs = all_kv.into_iter().map(|(k, v, _)| (k, v)).collect();
+                        reduce_sorted(&ctx, reduce, kvs, Some(&self.progress))
                     }
-                    let result = reduce(key, values);
-                    writeln!(file, "{}", result).expect("Failed to write");
+                    Reducer::Tagged(join) => join_sorted(&ctx, join, all_kv, &self.progress),
+                };
+                if self.progress.is_aborted() {
+                    return Ok(self.abort());
                 }
-                file.flush().expect("Failed to flush");
+                ctx.incr("reduce_output_records", records.len() as i64);
+                format::write_output(&temp_filename, data.output_format, &records)
+                    .map_err(|e| format!("cannot write {}: {}", temp_filename, e))?;
 
-                fs::rename(&temp_filename, &final_filename).expect("Failed to rename temp file");
+                fs::rename(&temp_filename, &final_filename)?;
 
-                Report::ReducerDone {
+                Ok(Report::ReducerDone {
                     taskid: data.task_id,
+                    counters: ctx.counters.take(),
+                })
+            }
+        }
+    }
+}
+
+/// Maps a split in skip mode. Records, as `format::split_records` defines them for the
+/// input format, are mapped a range at a time: the map function is called on contiguous
+/// slices of the split, so it must not depend on where a chunk starts or ends. A range
+/// whose map call panics is bisected until the records causing the panic are isolated and
+/// skipped. Returns the map output and the skipped `[start, end)` record ranges.
+fn map_skipping(
+    ctx: &JobContext,
+    map: MapFunction,
+    filename: &str,
+    content: &str,
+    input_format: InputFormat,
+) -> (Vec<KeyValue>, Vec<(u64, u64)>) {
+    // offsets[i] is where record i starts; records are contiguous slices of `content`
+    let mut offsets = vec![0];
+    for record in format::split_records(content, input_format) {
+        offsets.push(offsets[offsets.len() - 1] + record.len());
+    }
+    let mut output = Vec::new();
+    let mut skipped: Vec<(u64, u64)> = Vec::new();
+    let mut ranges = vec![(0, offsets.len() - 1)];
+    while let Some((start, end)) = ranges.pop() {
+        if start == end {
+            continue;
+        }
+        // a panicking call must not leave its counter updates behind
+        let counters = ctx.counters.borrow().clone();
+        let chunk = content[offsets[start]..offsets[end]].to_string();
+        match catch_quietly(|| map(ctx, filename.to_string(), chunk)) {
+            Ok(kvs) => output.extend(kvs),
+            Err(panic) => {
+                *ctx.counters.borrow_mut() = counters;
+                if end - start == 1 {
+                    log::debug!(
+                        "Skipping record {} of {}: {}",
+                        start,
+                        filename,
+                        panic_message(&*panic)
+                    );
+                    match skipped.last_mut() {
+                        Some(last) if last.1 == start as u64 => last.1 = end as u64,
+                        _ => skipped.push((start as u64, end as u64)),
+                    }
+                } else {
+                    let mid = (start + end) / 2;
+                    ranges.push((mid, end));
+                    ranges.push((start, mid));
                 }
             }
         }
     }
+    let count: u64 = skipped.iter().map(|(start, end)| end - start).sum();
+    if count > 0 {
+        log::warn!("Skipped {} bad records in {}", count, filename);
+        ctx.incr("skipped_records", count as i64);
+    }
+    (output, skipped)
+}
+
+thread_local! {
+    static QUIET_PANICS: Cell<bool> = const { Cell::new(false) };
 }
 
-fn map(_filename: &String, content: String) -> Vec<KeyValue> {
-    let mut d: Vec<(&str, u32)> = content.split(" ").map(|x| (x, 1 as u32)).collect();
-    d.sort_by_key(|f| f.0);
-    let mut i = 0;
-    let mut kvs = Vec::new();
-    while i < d.len() {
-        let key = d[i].0;
-        let mut values = Vec::new();
-        while i < d.len() && key == d[i].0 {
-            values.push(d[i].1);
-            i += 1;
+static QUIET_HOOK: Once = Once::new();
+
+/// Runs `f`, catching a panic without printing it. The panic hook is process-wide, so it
+/// is wrapped once to stay silent only on threads inside this function.
+fn catch_quietly<R>(f: impl FnOnce() -> R) -> std::thread::Result<R> {
+    QUIET_HOOK.call_once(|| {
+        let hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if !QUIET_PANICS.with(Cell::get) {
+                hook(info);
+            }
+        }));
+    });
+    QUIET_PANICS.with(|quiet| quiet.set(true));
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    QUIET_PANICS.with(|quiet| quiet.set(false));
+    result
+}
+
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> String {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => panic
+            .downcast_ref::<String>()
+            .cloned()
+            .unwrap_or_else(|| "unknown panic".to_string()),
+    }
+}
+
+/// Groups key-sorted pairs by key and applies `f` to each group. With `progress`, the
+/// share of records merged moves it from one half to done.
+fn reduce_sorted(
+    ctx: &JobContext,
+    f: ReduceFunction,
+    kvs: Vec<(String, String)>,
+    progress: Option<&Progress>,
+) -> Vec<(String, String)> {
+    let total = kvs.len();
+    let mut merged = 0;
+    let mut out = Vec::new();
+    let mut iter = kvs.into_iter().peekable();
+    while let Some((key, value)) = iter.next() {
+        let mut values = vec![value];
+        while let Some((_, v)) = iter.next_if(|(k, _)| *k == key) {
+            values.push(v);
+        }
+        merged += values.len();
+        if let Some(progress) = progress {
+            if progress.is_aborted() {
+                break;
+            }
+            progress.set(0.5 + 0.5 * merged as f64 / total as f64);
+        }
+        let result = f(ctx, key.clone(), values);
+        out.push((key, result));
+    }
+    out
+}
+
+/// Groups key-sorted tagged records by key, then by tag, and applies `f` to each key.
+fn join_sorted(
+    ctx: &JobContext,
+    f: JoinFunction,
+    records: Vec<(String, String, Option<String>)>,
+    progress: &Progress,
+) -> Vec<(String, String)> {
+    let total = records.len();
+    let mut merged = 0;
+    let mut out = Vec::new();
+    let mut iter = records.into_iter().peekable();
+    while let Some((key, value, tag)) = iter.next() {
+        let mut by_tag: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        by_tag
+            .entry(tag.unwrap_or_default())
+            .or_default()
+            .push(value);
+        while let Some((_, v, t)) = iter.next_if(|(k, _, _)| *k == key) {
+            by_tag.entry(t.unwrap_or_default()).or_default().push(v);
+        }
+        merged += by_tag.values().map(Vec::len).sum::<usize>();
+        if progress.is_aborted() {
+            break;
+        }
+        progress.set(0.5 + 0.5 * merged as f64 / total as f64);
+        for result in f(ctx, key.clone(), by_tag) {
+            out.push((key.clone(), result));
         }
-        let result = reduce(key.to_string(), values);
-        let kv: KeyValue = result
-            .split_once(" ")
-            .map(|(k, v)| KeyValue {
-                key: k.to_string(),
-                value: v.to_string(),
-            })
-            .expect("Unable to Convert to key Value form");
-        kvs.push(kv);
     }
-    kvs
+    out
 }
 
-pub fn ihash(key: &str) -> u32 {
-    let mut hasher = DefaultHasher::new();
-    key.hash(&mut hasher);
-    hasher.finish() as u32
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map_lines(ctx: &JobContext, _filename: String, contents: String) -> Vec<KeyValue> {
+        contents
+            .lines()
+            .map(|line| {
+                ctx.incr("mapped", 1);
+                if line == "42" {
+                    panic!("bad record");
+                }
+                KeyValue {
+                    key: line.to_string(),
+                    value: "1".to_string(),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn skip_mode_skips_only_the_bad_record() {
+        let ctx = JobContext {
+            config: BTreeMap::new(),
+            task_id: 0,
+            attempt_id: 0,
+            partition: None,
+            counters: RefCell::new(BTreeMap::new()),
+            cache_files: BTreeMap::new(),
+            datasets: Vec::new(),
+            tag: None,
+        };
+        let content: String = (1..=100).map(|i| format!("{}\n", i)).collect();
+        let (output, skipped) =
+            map_skipping(&ctx, map_lines, "in.txt", &content, InputFormat::Text);
+
+        assert_eq!(skipped, [(41, 42)]);
+        let mut keys: Vec<u32> = output.iter().map(|kv| kv.key.parse().unwrap()).collect();
+        keys.sort();
+        let expected: Vec<u32> = (1..=100).filter(|i| *i != 42).collect();
+        assert_eq!(keys, expected);
+        // calls that panicked leave no counter updates behind
+        let counters = ctx.counters.borrow();
+        assert_eq!(counters["mapped"], 99);
+        assert_eq!(counters["skipped_records"], 1);
+    }
 }